@@ -29,12 +29,14 @@
 // along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
 
 use crate::configuration::DEFAULT_CONFIGURATION_FILE;
-use clap::{crate_version, App, Arg};
+use clap::{crate_version, App, Arg, SubCommand};
 use std::path::PathBuf;
 
 #[derive(Debug)]
 pub struct CliConfiguration {
     pub configuration_file: PathBuf,
+    /// Only check the database compatibility and exit
+    pub check_database: bool,
 }
 
 pub fn parse() -> CliConfiguration {
@@ -51,6 +53,10 @@ pub fn parse() -> CliConfiguration {
                 .help("Sets a custom config file")
                 .takes_value(true),
         )
+        .subcommand(
+            SubCommand::with_name("check-database")
+                .about("Checks the database schema and privileges, and exits"),
+        )
         .get_matches();
 
     CliConfiguration {
@@ -58,5 +64,6 @@ pub fn parse() -> CliConfiguration {
             .value_of("config")
             .expect("No configuration file specified")
             .into(),
+        check_database: matches.subcommand_matches("check-database").is_some(),
     }
 }
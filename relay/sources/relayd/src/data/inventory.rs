@@ -0,0 +1,81 @@
+// Copyright 2019 Normation SAS
+//
+// This file is part of Rudder.
+//
+// Rudder is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In accordance with the terms of section 7 (7. Additional Terms.) of
+// the GNU General Public License version 3, the copyright holders add
+// the following Additional permissions:
+// Notwithstanding to the terms of section 5 (5. Conveying Modified Source
+// Versions) and 6 (6. Conveying Non-Source Forms.) of the GNU General
+// Public License version 3, when you create a Related Module, this
+// Related Module is not considered as a part of the work and may be
+// distributed under the license agreement of your choice.
+// A "Related Module" means a set of sources files including their
+// documentation that, without modification of the Source Code, enables
+// supplementary functions or services in addition to those offered by
+// the Software.
+//
+// Rudder is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::path::Path;
+
+/// Incoming inventories are received in a different directory
+/// depending on the acceptation status of the node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InventoryType {
+    /// Inventory of a node that is not accepted yet, received in `incoming`
+    New,
+    /// Inventory of an accepted node, received in `accepted-nodes-updates`
+    Update,
+}
+
+impl InventoryType {
+    /// Guesses the type from the directory containing the file
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path
+            .parent()
+            .and_then(|directory| directory.file_name())
+            .and_then(|name| name.to_str())
+        {
+            Some("incoming") => Some(InventoryType::New),
+            Some("accepted-nodes-updates") => Some(InventoryType::Update),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inventory_type() {
+        assert_eq!(
+            InventoryType::from_path(Path::new(
+                "/var/rudder/inventories/processing/incoming/node.ocs"
+            )),
+            Some(InventoryType::New)
+        );
+        assert_eq!(
+            InventoryType::from_path(Path::new(
+                "/var/rudder/inventories/processing/accepted-nodes-updates/node.ocs.gz"
+            )),
+            Some(InventoryType::Update)
+        );
+        assert_eq!(
+            InventoryType::from_path(Path::new("/var/rudder/inventories/node.ocs")),
+            None
+        );
+    }
+}
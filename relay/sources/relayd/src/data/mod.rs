@@ -28,5 +28,6 @@
 // You should have received a copy of the GNU General Public License
 // along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
 
+pub mod inventory;
 pub mod nodes;
 pub mod reporting;
@@ -28,17 +28,22 @@
 // You should have received a copy of the GNU General Public License
 // along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::{data::nodes::NodeId, error::Error, output::database::schema::ruddersysevents};
+use crate::{
+    configuration::ParsingMode,
+    data::nodes::{NodeId, NodesList},
+    error::{Error, Expected, ParseError},
+};
 use chrono::prelude::*;
 use nom::types::CompleteStr;
 use nom::*;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use slog::{slog_debug, slog_warn};
 use slog_scope::{debug, warn};
 use std::{
     fmt::{self, Display},
     str::FromStr,
 };
+use toml;
 
 /*
 "^@@(?P<policy>.*)@@(?P<event_type>.*)@@(?P<rule_id>.*)@@(?P<directive_id>.*)@@(?P<serial>.*)@@
@@ -47,43 +52,73 @@ use std::{
 
 // A detail log entry
 #[derive(Debug, PartialEq, Eq)]
-struct LogEntry {
-    event_type: AgentLogLevel,
-    msg: String,
+struct LogEntry<'a> {
+    log_datetime: Option<DateTime<FixedOffset>>,
+    event_type: EventType,
+    msg: &'a str,
 }
 
-type AgentLogLevel = &'static str;
+/// Prefix of an agent log line, and the event type of the matching reports
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct LogPrefix {
+    pub prefix: String,
+    pub event_type: EventType,
+}
 
-named!(
-    agent_log_level<CompleteStr, AgentLogLevel>,
-    alt!(
+impl LogPrefix {
+    fn new(prefix: &str, event_type: EventType) -> Self {
+        LogPrefix {
+            prefix: prefix.to_string(),
+            event_type,
+        }
+    }
+}
+
+/// Prefixes of the CFEngine and ncf logs, used when none are configured
+pub fn default_log_prefixes() -> Vec<LogPrefix> {
+    vec![
         // CFEngine logs
-        tag_s!("CRITICAL:")   => { |_| "log_warn" }  |
-        tag_s!("   error:")   => { |_| "log_warn" }  |
-        tag_s!(" warning:")   => { |_| "log_warn" }  |
-        tag_s!("  notice:")   => { |_| "log_info" }  |
-        tag_s!("    info:")   => { |_| "log_info" }  |
-        tag_s!(" verbose:")   => { |_| "log_debug" } |
-        tag_s!("   debug:")   => { |_| "log_debug" } |
+        LogPrefix::new("CRITICAL:", EventType::LogWarn),
+        LogPrefix::new("   error:", EventType::LogWarn),
+        LogPrefix::new(" warning:", EventType::LogWarn),
+        LogPrefix::new("  notice:", EventType::LogInfo),
+        LogPrefix::new("    info:", EventType::LogInfo),
+        LogPrefix::new(" verbose:", EventType::LogDebug),
+        LogPrefix::new("   debug:", EventType::LogDebug),
         // ncf logs
-        tag_s!("R: [FATAL]")  => { |_| "log_warn" }  |
-        tag_s!("R: [ERROR]")  => { |_| "log_warn" }  |
-        tag_s!("R: [INFO]")   => { |_| "log_info" }  |
-        tag_s!("R: [DEBUG]")  => { |_| "log_debug" } |
+        LogPrefix::new("R: [FATAL]", EventType::LogWarn),
+        LogPrefix::new("R: [ERROR]", EventType::LogWarn),
+        LogPrefix::new("R: [INFO]", EventType::LogInfo),
+        LogPrefix::new("R: [DEBUG]", EventType::LogDebug),
         // ncf non-standard log
-        tag_s!("R: WARNING")  => { |_| "log_warn" }  |
+        LogPrefix::new("R: WARNING", EventType::LogWarn),
         // CFEngine stdlib log
-        tag_s!("R: DEBUG")    => { |_| "log_debug" } |
-        // Untagged non-Rudder reports report, assume info
-        non_rudder_report_begin
-    )
-);
+        LogPrefix::new("R: DEBUG", EventType::LogDebug),
+    ]
+}
 
-named!(non_rudder_report_begin<CompleteStr, AgentLogLevel>,
+/// Prefixes are tried in order, the first matching one gives the event type
+fn agent_log_level<'a>(
+    input: CompleteStr<'a>,
+    prefixes: &[LogPrefix],
+) -> IResult<CompleteStr<'a>, EventType> {
+    for log_prefix in prefixes {
+        if input.starts_with(log_prefix.prefix.as_str()) {
+            return Ok((
+                CompleteStr(&input[log_prefix.prefix.len()..]),
+                log_prefix.event_type.clone(),
+            ));
+        }
+    }
+    // Untagged non-Rudder reports report, assume info
+    non_rudder_report_begin(input)
+}
+
+named!(non_rudder_report_begin<CompleteStr, EventType>,
     do_parse!(
     tag_s!("R: ") >>
     not!(tag_s!("@@")) >>
-    ("log_info")
+    (EventType::LogInfo)
     )
 );
 
@@ -94,123 +129,387 @@ named!(rudder_report_begin<CompleteStr, &str>,
     )
 );
 
-named!(simpleline<CompleteStr, String>, do_parse!(
-    not!(alt!(rudder_report_begin | agent_log_level)) >>
-    res: take_until_and_consume_s!("\n") >>
-    (res.to_string())
-));
+/// A line without its line break, which can be missing at the end of the file
+fn line(input: CompleteStr) -> IResult<CompleteStr, CompleteStr> {
+    match input.find('\n') {
+        Some(end) => Ok((CompleteStr(&input[end + 1..]), CompleteStr(&input[..end]))),
+        None if !input.is_empty() => Ok((CompleteStr(&input[input.len()..]), input)),
+        None => Err(nom::Err::Error(error_position!(
+            input,
+            ErrorKind::TakeUntilAndConsume
+        ))),
+    }
+}
+
+fn is_datetime_char(c: char) -> bool {
+    c.is_ascii_digit() || c == '-' || c == ':' || c == '+'
+}
 
-named!(multilines<CompleteStr, String>,
-do_parse!(
-    // at least one
-    res: many1!(simpleline) >>
-    // TODO perf: avoid reallocating everything twice and use the source slice
-    (res.join("\n"))
+// Timestamp added by the agent at the beginning of each line, followed by a space
+named!(line_datetime<CompleteStr, DateTime<FixedOffset>>, do_parse!(
+    datetime: map_res!(
+        recognize!(tuple!(
+            take_while1_s!(is_datetime_char),
+            tag_s!(" "),
+            take_while1_s!(is_datetime_char)
+        )),
+        |d: CompleteStr| DateTime::parse_from_str(d.0, REPORT_DATETIME_FORMAT)
+    ) >>
+    tag_s!(" ") >>
+    (datetime)
 ));
 
-named!(
-    log_entry<CompleteStr, LogEntry>,
-    do_parse!(
-        level: agent_log_level
-            >> opt!(space)
-            >> msg: multilines
-            >> (LogEntry {
-                event_type: level,
-                msg,
-            })
+fn simpleline<'a>(
+    input: CompleteStr<'a>,
+    prefixes: &[LogPrefix],
+) -> IResult<CompleteStr<'a>, CompleteStr<'a>> {
+    do_parse!(input,
+        not!(call!(entry_begin, prefixes)) >>
+        res: line >>
+        (res)
     )
-);
+}
 
-named!(log_entries<CompleteStr, Vec<LogEntry>>, many0!(log_entry));
+/// Beginning of a report or of a log entry
+fn entry_begin<'a>(input: CompleteStr<'a>, prefixes: &[LogPrefix]) -> IResult<CompleteStr<'a>, ()> {
+    preceded!(
+        input,
+        opt!(line_datetime),
+        alt!(
+            rudder_report_begin                => { |_| () } |
+            call!(agent_log_level, prefixes)   => { |_| () }
+        )
+    )
+}
 
-named!(parse_runlog<CompleteStr, Vec<RawReport>>,
-    many1!(
-        report
+/// Removes the line break at the end of a multiline message, if any
+fn without_line_break(lines: CompleteStr) -> &str {
+    if lines.ends_with("\r\n") {
+        &lines.0[..lines.len() - 2]
+    } else if lines.ends_with('\n') {
+        &lines.0[..lines.len() - 1]
+    } else {
+        lines.0
+    }
+}
+
+// Lines are consecutive in the source, so the message is the source slice
+fn multilines<'a>(
+    input: CompleteStr<'a>,
+    prefixes: &[LogPrefix],
+) -> IResult<CompleteStr<'a>, &'a str> {
+    map!(
+        input,
+        // at least one
+        recognize!(many1!(call!(simpleline, prefixes))),
+        without_line_break
     )
-);
+}
 
-named!(report<CompleteStr, RawReport>, do_parse!(
-    // TODO NOT CORRECT
-    // pas de line break dans une ligne
-    // gérer les reports interrompus
-    logs: log_entries >>
-    rudder_report_begin >>
-    policy: take_until_and_consume_s!("@@") >>
-    event_type: take_until_and_consume_s!("@@") >>
-    rule_id: take_until_and_consume_s!("@@") >>
-    directive_id: take_until_and_consume_s!("@@") >>
-    serial: take_until_and_consume_s!("@@") >>
-    component: take_until_and_consume_s!("@@") >>
-    key_value: take_until_and_consume_s!("@@") >>
-    start_datetime: take_until_and_consume_s!("##") >>
-    node_id: take_until_and_consume_s!("@#") >>
-    msg: multilines >>
-        (RawReport {
-            report: Report {
-           // FIXME execution date should be generated at execution
-           // We could skip parsing it but it would prevent consistency check that cannot
-           // be done once inserted.
-            execution_datetime: DateTime::parse_from_str(&start_datetime.to_string(), "%Y-%m-%d %H:%M:%S%z").unwrap(),
-            node_id: node_id.to_string(),
-            rule_id: rule_id.to_string(),
-            directive_id: directive_id.to_string(),
-            serial: serial.parse::<i32>().unwrap(),
-            component: component.to_string(),
-            key_value: key_value.to_string(),
-            start_datetime: DateTime::parse_from_str(&start_datetime.to_string(), "%Y-%m-%d %H:%M:%S%z").unwrap(),
-            event_type: event_type.to_string(),
-            msg: msg.to_string(),
-            policy: policy.to_string(),
-        },
-        logs
+fn log_entry<'a>(
+    input: CompleteStr<'a>,
+    prefixes: &[LogPrefix],
+) -> IResult<CompleteStr<'a>, LogEntry<'a>> {
+    do_parse!(input,
+        log_datetime: opt!(line_datetime) >>
+        level: call!(agent_log_level, prefixes) >>
+        opt!(space) >>
+        msg: call!(multilines, prefixes) >>
+        (LogEntry {
+            log_datetime,
+            event_type: level,
+            msg,
         })
+    )
+}
+
+fn log_entries<'a>(
+    input: CompleteStr<'a>,
+    prefixes: &[LogPrefix],
+) -> IResult<CompleteStr<'a>, Vec<LogEntry<'a>>> {
+    many0!(input, call!(log_entry, prefixes))
+}
+
+/// Date format used by the agent in reports
+const REPORT_DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S%z";
+/// Same as `REPORT_DATETIME_FORMAT`, with the colon the agent puts in the offset
+const REPORT_DATETIME_OUTPUT_FORMAT: &str = "%Y-%m-%d %H:%M:%S%:z";
+
+fn report<'a>(
+    input: CompleteStr<'a>,
+    prefixes: &[LogPrefix],
+) -> IResult<CompleteStr<'a>, RawReport<'a>> {
+    do_parse!(input,
+        // TODO NOT CORRECT
+        // pas de line break dans une ligne
+        // gérer les reports interrompus
+        logs: call!(log_entries, prefixes) >>
+        log_datetime: opt!(line_datetime) >>
+        add_return_error!(Expected::ReportBegin.code(), rudder_report_begin) >>
+        report: call!(report_content, prefixes) >>
+        (RawReport { report: ReportView { log_datetime, ..report }, logs })
+    )
+}
+
+/// A report field, up to its separator which has to be on the same line
+fn field(input: CompleteStr, separator: Expected) -> IResult<CompleteStr, CompleteStr> {
+    let line_end = input.find('\n').unwrap_or_else(|| input.len());
+    match input[..line_end].find(separator.token()) {
+        Some(end) => Ok((
+            CompleteStr(&input[end + separator.token().len()..]),
+            CompleteStr(&input[..end]),
+        )),
+        None => Err(nom::Err::Error(error_position!(input, separator.code()))),
+    }
+}
+
+/// A report field converted by `convert`, located at the field when the conversion fails
+fn converted_field<'a, T, E>(
+    input: CompleteStr<'a>,
+    separator: Expected,
+    expected: Expected,
+    convert: impl Fn(&'a str) -> Result<T, E>,
+) -> IResult<CompleteStr<'a>, T> {
+    let (rest, value) = field(input, separator)?;
+    match convert(value.0) {
+        Ok(converted) => Ok((rest, converted)),
+        Err(_) => Err(nom::Err::Error(error_position!(input, expected.code()))),
+    }
+}
+
+/// The fields of a report without its `R: @@` prefix, up to its message
+fn report_fields(input: CompleteStr) -> IResult<CompleteStr, ReportView> {
+    do_parse!(input,
+        policy: call!(field, Expected::FieldSeparator) >>
+        event_type: call!(field, Expected::FieldSeparator) >>
+        rule_id: call!(field, Expected::FieldSeparator) >>
+        directive_id: call!(field, Expected::FieldSeparator) >>
+        serial: call!(
+            converted_field,
+            Expected::FieldSeparator,
+            Expected::Serial,
+            |s: &str| s.parse::<i32>()
+        ) >>
+        component: call!(field, Expected::FieldSeparator) >>
+        key_value: call!(field, Expected::FieldSeparator) >>
+        start_datetime: call!(
+            converted_field,
+            Expected::DateSeparator,
+            Expected::Datetime,
+            |d: &str| DateTime::parse_from_str(d, REPORT_DATETIME_FORMAT)
+        ) >>
+        node_id: call!(field, Expected::NodeSeparator) >>
+            (ReportView {
+               // FIXME execution date should be generated at execution
+               // We could skip parsing it but it would prevent consistency check that cannot
+               // be done once inserted.
+                execution_datetime: start_datetime,
+                node_id: node_id.0,
+                rule_id: rule_id.0,
+                directive_id: directive_id.0,
+                serial,
+                component: component.0,
+                key_value: key_value.0,
+                start_datetime,
+                event_type: event_type.0,
+                msg: "",
+                policy: policy.0,
+                log_datetime: None,
+            })
+    )
+}
+
+/// A report without its `R: @@` prefix, its message ends before the next report or log entry
+fn report_content<'a>(
+    input: CompleteStr<'a>,
+    prefixes: &[LogPrefix],
+) -> IResult<CompleteStr<'a>, ReportView<'a>> {
+    do_parse!(input,
+        report: report_fields >>
+        msg: call!(multilines, prefixes) >>
+        (ReportView { msg, ..report })
+    )
+}
+
+// A single report, in the format produced by its `Display` implementation.
+// Its message is the rest of the input, which can be empty or end with a line break.
+named!(single_report<CompleteStr, ReportView>, do_parse!(
+    add_return_error!(Expected::FieldSeparator.code(), tag_s!("@@")) >>
+    report: report_fields >>
+    msg: rest >>
+    (ReportView { msg: msg.0, ..report })
 ));
 
 #[derive(Debug, PartialEq, Eq)]
-pub struct RawReport {
-    report: Report,
-    logs: Vec<LogEntry>,
+pub struct RawReport<'a> {
+    report: ReportView<'a>,
+    logs: Vec<LogEntry<'a>>,
 }
 
-impl RawReport {
+impl<'a> RawReport<'a> {
     fn into_reports(self) -> Vec<Report> {
         let mut res = vec![];
         for log in self.logs {
-            res.push(Report {
-                event_type: log.event_type.to_string(),
+            let mut report = Report::from(ReportView {
+                log_datetime: log.log_datetime,
                 msg: log.msg,
-                ..self.report.clone()
-            })
+                ..self.report
+            });
+            report.event_type = log.event_type;
+            res.push(report)
         }
-        res.push(self.report);
+        res.push(Report::from(self.report));
         res
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Insertable)]
-#[table_name = "ruddersysevents"]
+/// A report borrowing its fields from the runlog content
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReportView<'a> {
+    pub start_datetime: DateTime<FixedOffset>,
+    pub rule_id: &'a str,
+    pub directive_id: &'a str,
+    pub component: &'a str,
+    pub key_value: &'a str,
+    pub event_type: &'a str,
+    pub msg: &'a str,
+    pub policy: &'a str,
+    pub node_id: &'a str,
+    pub execution_datetime: DateTime<FixedOffset>,
+    pub serial: i32,
+    pub log_datetime: Option<DateTime<FixedOffset>>,
+}
+
+impl<'a> From<ReportView<'a>> for Report {
+    fn from(view: ReportView<'a>) -> Self {
+        Report {
+            start_datetime: view.start_datetime,
+            rule_id: view.rule_id.to_string(),
+            directive_id: view.directive_id.to_string(),
+            component: view.component.to_string(),
+            key_value: view.key_value.to_string(),
+            event_type: EventType::from(view.event_type),
+            msg: view.msg.to_string(),
+            policy: view.policy.to_string(),
+            node_id: view.node_id.to_string(),
+            execution_datetime: view.execution_datetime,
+            serial: view.serial,
+            log_datetime: view.log_datetime,
+        }
+    }
+}
+
+/// Type of a report, stored as text in the database
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum EventType {
+    ResultSuccess,
+    ResultRepaired,
+    ResultError,
+    ResultNa,
+    AuditCompliant,
+    AuditNoncompliant,
+    AuditError,
+    AuditNa,
+    LogRepaired,
+    LogWarn,
+    LogInfo,
+    LogDebug,
+    LogTrace,
+    Control,
+    /// Types sent by newer agents, kept as is
+    Unknown(String),
+}
+
+impl EventType {
+    pub fn as_str(&self) -> &str {
+        match *self {
+            EventType::ResultSuccess => "result_success",
+            EventType::ResultRepaired => "result_repaired",
+            EventType::ResultError => "result_error",
+            EventType::ResultNa => "result_na",
+            EventType::AuditCompliant => "audit_compliant",
+            EventType::AuditNoncompliant => "audit_noncompliant",
+            EventType::AuditError => "audit_error",
+            EventType::AuditNa => "audit_na",
+            EventType::LogRepaired => "log_repaired",
+            EventType::LogWarn => "log_warn",
+            EventType::LogInfo => "log_info",
+            EventType::LogDebug => "log_debug",
+            EventType::LogTrace => "log_trace",
+            EventType::Control => "control",
+            EventType::Unknown(ref event_type) => event_type,
+        }
+    }
+
+    /// Logs only give context and do not count in compliance
+    pub fn is_log(&self) -> bool {
+        match *self {
+            EventType::LogRepaired
+            | EventType::LogWarn
+            | EventType::LogInfo
+            | EventType::LogDebug
+            | EventType::LogTrace => true,
+            _ => false,
+        }
+    }
+}
+
+impl<'a> From<&'a str> for EventType {
+    fn from(event_type: &'a str) -> Self {
+        match event_type {
+            "result_success" => EventType::ResultSuccess,
+            "result_repaired" => EventType::ResultRepaired,
+            "result_error" => EventType::ResultError,
+            "result_na" => EventType::ResultNa,
+            "audit_compliant" => EventType::AuditCompliant,
+            "audit_noncompliant" => EventType::AuditNoncompliant,
+            "audit_error" => EventType::AuditError,
+            "audit_na" => EventType::AuditNa,
+            "log_repaired" => EventType::LogRepaired,
+            "log_warn" => EventType::LogWarn,
+            "log_info" => EventType::LogInfo,
+            "log_debug" => EventType::LogDebug,
+            "log_trace" => EventType::LogTrace,
+            "control" => EventType::Control,
+            _ => EventType::Unknown(event_type.to_string()),
+        }
+    }
+}
+
+impl Display for EventType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for EventType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for EventType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(EventType::from(String::deserialize(deserializer)?.as_str()))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Report {
-    #[column_name = "executiondate"]
     pub start_datetime: DateTime<FixedOffset>,
-    #[column_name = "ruleid"]
     pub rule_id: String,
-    #[column_name = "directiveid"]
     pub directive_id: String,
     pub component: String,
-    #[column_name = "keyvalue"]
     pub key_value: String,
-    // Not parsed as we do not use it and do not want to prevent future changes
-    #[column_name = "eventtype"]
-    pub event_type: String,
-    #[column_name = "msg"]
+    // Unknown types are kept as is, to not prevent future changes
+    pub event_type: EventType,
     pub msg: String,
-    #[column_name = "policy"]
     pub policy: String,
-    #[column_name = "nodeid"]
     pub node_id: NodeId,
-    #[column_name = "executiontimestamp"]
     pub execution_datetime: DateTime<FixedOffset>,
     pub serial: i32,
+    /// Timestamp of the line in the runlog, only present in recent agents output
+    pub log_datetime: Option<DateTime<FixedOffset>>,
 }
 
 impl Display for Report {
@@ -225,21 +524,40 @@ impl Display for Report {
             self.serial,
             self.component,
             self.key_value,
-            self.start_datetime,
+            self.start_datetime.format(REPORT_DATETIME_OUTPUT_FORMAT),
             self.node_id,
             self.msg,
         )
     }
 }
 
+impl FromStr for Report {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match single_report(CompleteStr::from(s)) {
+            Ok((_, report)) => Ok(Report::from(report)),
+            Err(e) => Err(Error::InvalidRunLog(ParseError::from_nom(s, e))),
+        }
+    }
+}
+
 impl RunLog {
-    fn from_reports(raw_reports: Vec<RawReport>) -> Result<Self, Error> {
+    fn from_reports(
+        header: Option<RunLogHeader>,
+        raw_reports: Vec<RawReport>,
+        rejected: Vec<RejectedSegment>,
+    ) -> Result<Self, Error> {
         let reports: Vec<Report> = raw_reports
             .into_iter()
             .flat_map(|x| x.into_reports())
             .collect();
 
         let info = match reports.first() {
+            // Nothing could be salvaged, the first error is the most relevant
+            None if !rejected.is_empty() => {
+                return Err(Error::InvalidRunLog(rejected[0].error.clone()))
+            }
             None => return Err(Error::EmptyRunlog),
             Some(report) => RunInfo {
                 node_id: report.node_id.clone(),
@@ -247,22 +565,72 @@ impl RunLog {
             },
         };
 
-        for report in &reports {
-            if info.node_id != report.node_id {
-                warn!("Wrong node id in report {:#?}", report; "component" => "parser");
+        Ok(RunLog {
+            header,
+            info,
+            metadata: RunMetadata::from_reports(&reports),
+            reports,
+            rejected,
+        })
+    }
+
+    /// Checks that the file name, the header, the reports and the nodes list all agree
+    /// on the node and the run, so that a node cannot report on behalf of another one.
+    ///
+    /// The signature only covers the reports, so the header of a signed runlog is
+    /// only trusted once it matches them.
+    pub fn validate(&self, file_info: &RunInfo, nodes: &NodesList) -> Result<(), Error> {
+        let inconsistent = |reason: String| {
+            warn!("Refusing runlog {:?}: {}", file_info, reason; "component" => "parser");
+            Err(Error::InconsistentRunlog(reason))
+        };
+
+        if !nodes.contains_key(&file_info.node_id) {
+            return Err(Error::UnknownNode(file_info.node_id.clone()));
+        }
+
+        if let Some(ref header) = self.header {
+            if let Some(report) = self.reports.iter().find(|report| {
+                report.node_id != header.node_id || report.start_datetime != header.timestamp
+            }) {
+                return inconsistent(format!(
+                    "header run {}@{} does not match signed report run {}@{}",
+                    header.timestamp, header.node_id, report.start_datetime, report.node_id
+                ));
+            }
+            if header.node_id != file_info.node_id {
+                return inconsistent(format!(
+                    "header node id {} does not match file name node id {}",
+                    header.node_id, file_info.node_id
+                ));
+            }
+            if header.timestamp != file_info.timestamp {
+                return inconsistent(format!(
+                    "header timestamp {} does not match file name timestamp {}",
+                    header.timestamp, file_info.timestamp
+                ));
+            }
+        }
+
+        for report in &self.reports {
+            if report.node_id != file_info.node_id {
+                return inconsistent(format!(
+                    "report node id {} does not match file name node id {}",
+                    report.node_id, file_info.node_id
+                ));
             }
-            if info.timestamp != report.start_datetime {
-                warn!(
-                    "Wrong execution timestamp in report {:#?}",
-                    report; "component" => "parser"
-                );
+            if report.start_datetime != file_info.timestamp {
+                return inconsistent(format!(
+                    "report timestamp {} does not match file name timestamp {}",
+                    report.start_datetime, file_info.timestamp
+                ));
             }
         }
-        Ok(RunLog { info, reports })
+        Ok(())
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct RunInfo {
     pub node_id: NodeId,
     pub timestamp: DateTime<FixedOffset>,
@@ -270,13 +638,23 @@ pub struct RunInfo {
 
 named!(parse_runinfo<CompleteStr, RunInfo>,
     do_parse!(
-        timestamp: take_until_and_consume_s!("@") >>
-        node_id: take_until_and_consume_s!(".") >>
-        tag_s!("log") >>
+        timestamp: add_return_error!(
+            Expected::TimestampSeparator.code(),
+            take_until_and_consume_s!("@")
+        ) >>
+        timestamp: add_return_error!(
+            Expected::Datetime.code(),
+            expr_res!(DateTime::parse_from_str(timestamp.0, "%+"))
+        ) >>
+        node_id: add_return_error!(
+            Expected::LogExtension.code(),
+            take_until_and_consume_s!(".")
+        ) >>
+        add_return_error!(Expected::LogExtension.code(), tag_s!("log")) >>
         (
             RunInfo {
                 // FIXME same timestamp format as in the reports?
-                timestamp: DateTime::parse_from_str(&timestamp.to_string(), "%+").unwrap(),
+                timestamp,
                 node_id: node_id.to_string(),
             }
         )
@@ -292,20 +670,209 @@ impl FromStr for RunInfo {
                 debug!("Parsed run info {:#?}", raw_runinfo.1; "component" => "parser");
                 Ok(raw_runinfo.1)
             }
-            Err(_) => Err(Error::InvalidRunInfo),
+            Err(e) => Err(Error::InvalidRunInfo(ParseError::from_nom(s, e))),
+        }
+    }
+}
+
+/// Marks the beginning of the reports in an enveloped runlog
+const DATA_BEGIN: &str = "---- data ----";
+/// Marks the end of the reports in an enveloped runlog, anything after it is ignored
+const DATA_END: &str = "---- end data ----";
+
+/// TOML header of enveloped runlogs
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RunLogHeader {
+    /// Version of the runlog format, used to select the body parser
+    pub format: u32,
+    #[serde(rename = "nodeid")]
+    pub node_id: NodeId,
+    #[serde(rename = "runtimestamp")]
+    pub timestamp: DateTime<FixedOffset>,
+    #[serde(rename = "keyhash")]
+    pub key_hash: String,
+    pub signature: String,
+}
+
+named!(runlog_envelope<CompleteStr, (CompleteStr, CompleteStr)>,
+    do_parse!(
+        header: take_until_and_consume_s!(DATA_BEGIN) >>
+        alt!(tag_s!("\r\n") | tag_s!("\n")) >>
+        data: take_until_and_consume_s!(DATA_END) >>
+        (header, data)
+    )
+);
+
+/// A runlog file split into its header and its data section, before report parsing
+#[derive(Debug, PartialEq, Eq)]
+pub struct RunLogEnvelope<'a> {
+    /// Legacy runlogs have no header
+    pub header: Option<RunLogHeader>,
+    pub data: &'a str,
+    /// Line of the file where the data section starts, to locate parsing errors
+    pub data_line: usize,
+}
+
+impl<'a> RunLogEnvelope<'a> {
+    /// Detects the runlog format: enveloped runlogs contain a data marker,
+    /// legacy runlogs only contain reports.
+    pub fn parse(s: &'a str) -> Result<Self, Error> {
+        match runlog_envelope(CompleteStr::from(s)) {
+            Ok((_, (header, data))) => Ok(RunLogEnvelope {
+                header: Some(toml::from_str(header.0)?),
+                data: data.0,
+                data_line: ParseError::new(s, data.0, DATA_BEGIN).line,
+            }),
+            // Data section was not closed, the file is likely truncated
+            Err(_) if s.contains(DATA_BEGIN) => Err(Error::InvalidRunLog(ParseError::new(
+                s,
+                &s[s.len()..],
+                DATA_END,
+            ))),
+            Err(_) => Ok(RunLogEnvelope {
+                header: None,
+                data: s,
+                data_line: 1,
+            }),
+        }
+    }
+
+    /// Parses the data section with the parser matching the format
+    pub fn runlog(&self, mode: ParsingMode, prefixes: &[LogPrefix]) -> Result<RunLog, Error> {
+        let format = self.header.as_ref().map(|h| h.format);
+        let (raw_reports, mut rejected) = match format {
+            // Legacy runlogs share the body format of version 0
+            None | Some(0) => parse_body_v0(self.data, mode, prefixes)
+                .map_err(|e| Error::InvalidRunLog(e.starting_at_line(self.data_line)))?,
+            Some(format) => return Err(Error::UnsupportedRunLogFormat(format)),
+        };
+        for segment in &mut rejected {
+            segment.error = segment.error.clone().starting_at_line(self.data_line);
         }
+        RunLog::from_reports(self.header.clone(), raw_reports, rejected)
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Part of a runlog skipped by the lenient parser
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectedSegment {
+    pub error: ParseError,
+    pub content: String,
+}
+
+/// Parses reports one by one. In lenient mode, an invalid report is skipped
+/// up to the beginning of the next report or log entry.
+fn parse_body_v0<'a>(
+    data: &'a str,
+    mode: ParsingMode,
+    prefixes: &[LogPrefix],
+) -> Result<(Vec<RawReport<'a>>, Vec<RejectedSegment>), ParseError> {
+    let mut reports = vec![];
+    let mut rejected = vec![];
+    let mut remaining = CompleteStr::from(data);
+
+    // Only trailing blank lines can remain after the last report
+    while !remaining.trim().is_empty() {
+        match report(remaining, prefixes) {
+            Ok((rest, raw_report)) => {
+                reports.push(raw_report);
+                remaining = rest;
+            }
+            Err(e) => {
+                let error = ParseError::from_nom(data, e);
+                if mode == ParsingMode::Strict {
+                    return Err(error);
+                }
+                let (rest, content) = skip_segment(remaining, prefixes);
+                rejected.push(RejectedSegment {
+                    error,
+                    content: content.trim_end().to_string(),
+                });
+                remaining = rest;
+            }
+        }
+    }
+    debug!("Parsed runlog {:#?}", reports; "component" => "parser");
+    Ok((reports, rejected))
+}
+
+/// Splits the input after the first line and the lines that do not start a report or a log entry
+fn skip_segment<'a>(
+    input: CompleteStr<'a>,
+    prefixes: &[LogPrefix],
+) -> (CompleteStr<'a>, CompleteStr<'a>) {
+    let mut rest = line(input).map(|(rest, _)| rest).unwrap_or(CompleteStr(""));
+    while !rest.is_empty() && simpleline(rest, prefixes).is_ok() {
+        rest = line(rest).map(|(rest, _)| rest).unwrap_or(CompleteStr(""));
+    }
+    let skipped = input.len() - rest.len();
+    (rest, CompleteStr(&input[..skipped]))
+}
+
+/// Information about the run, extracted from its `control` reports
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RunMetadata {
+    /// Node configuration id the agent ran with, from the `start` or `end` report
+    pub config_id: Option<String>,
+    /// An `end` report was received, which means the run was not interrupted
+    pub complete: bool,
+    /// From the `agent_version` report, if any
+    pub agent_version: Option<String>,
+    /// From the `capabilities` report, given as a comma-separated list
+    pub capabilities: Vec<String>,
+}
+
+impl RunMetadata {
+    pub fn from_reports(reports: &[Report]) -> Self {
+        let mut metadata = RunMetadata::default();
+        let mut end_config_id = None;
+
+        for report in reports
+            .iter()
+            .filter(|r| r.event_type == EventType::Control)
+        {
+            match report.component.as_ref() {
+                "start" => metadata.config_id = Some(report.key_value.clone()),
+                "end" => {
+                    metadata.complete = true;
+                    end_config_id = Some(report.key_value.clone());
+                }
+                "agent_version" => metadata.agent_version = Some(report.key_value.clone()),
+                "capabilities" => {
+                    metadata.capabilities = report
+                        .key_value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|c| !c.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                }
+                _ => (),
+            }
+        }
+        // The start report may be missing if the beginning of the run was not sent
+        metadata.config_id = metadata.config_id.or(end_config_id);
+        metadata
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RunLog {
+    pub header: Option<RunLogHeader>,
     pub info: RunInfo,
+    pub metadata: RunMetadata,
     pub reports: Vec<Report>,
+    /// Invalid parts of the runlog, only filled in lenient mode
+    #[serde(skip)]
+    pub rejected: Vec<RejectedSegment>,
 }
 
 impl Display for RunLog {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         for report in &self.reports {
+            if let Some(log_datetime) = report.log_datetime {
+                write!(f, "{} ", log_datetime.format(REPORT_DATETIME_OUTPUT_FORMAT))?
+            }
             writeln!(f, "R: {:}", report)?
         }
         Ok(())
@@ -316,52 +883,131 @@ impl FromStr for RunLog {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match parse_runlog(CompleteStr::from(s)) {
-            Ok(raw_runlog) => {
-                debug!("Parsed runlog {:#?}", raw_runlog.1; "component" => "parser");
-                Ok(Self::from_reports(raw_runlog.1)?)
-            }
-            Err(_) => Err(Error::InvalidRunInfo),
-        }
+        RunLogEnvelope::parse(s)?.runlog(ParsingMode::Strict, &default_log_prefixes())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::data::nodes::parse_nodeslist;
+    use proptest::prelude::*;
     use std::fs::read_to_string;
 
+    /// Fields cannot contain the separators
+    const FIELD: &str = "[a-zA-Z0-9_ .-]{0,20}";
+    /// Message lines cannot look like the beginning of a report or a log entry
+    /// and can be empty, including the last one to end the message with a line break
+    const MESSAGE: &str =
+        "([a-zA-Z0-9][a-zA-Z0-9 .,'/_-]{0,30})?(\n([a-zA-Z0-9][a-zA-Z0-9 .,'/_-]{0,30})?){0,3}";
+
+    prop_compose! {
+        fn arb_datetime()(secs in 0i64..4_000_000_000, minutes in -720i32..840) -> DateTime<FixedOffset> {
+            FixedOffset::east(minutes * 60).timestamp(secs, 0)
+        }
+    }
+
+    prop_compose! {
+        fn arb_report()(
+            fields in prop::collection::vec(FIELD, 6),
+            serial in any::<i32>(),
+            datetime in arb_datetime(),
+            log_datetime in prop::option::of(arb_datetime()),
+            node_id in "[a-zA-Z0-9-]{1,36}",
+            msg in MESSAGE
+        ) -> Report {
+            Report {
+                policy: fields[0].clone(),
+                event_type: EventType::from(fields[1].as_str()),
+                rule_id: fields[2].clone(),
+                directive_id: fields[3].clone(),
+                component: fields[4].clone(),
+                key_value: fields[5].clone(),
+                serial,
+                start_datetime: datetime,
+                execution_datetime: datetime,
+                node_id,
+                msg,
+                log_datetime,
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn report_display_round_trips(report in arb_report()) {
+            // The line timestamp is not part of the report content
+            let report = Report { log_datetime: None, ..report };
+            prop_assert_eq!(Report::from_str(&report.to_string()).unwrap(), report);
+        }
+
+        #[test]
+        fn runlog_display_round_trips(reports in prop::collection::vec(arb_report(), 1..10)) {
+            let run = RunLog {
+                header: None,
+                info: RunInfo {
+                    node_id: reports[0].node_id.clone(),
+                    timestamp: reports[0].start_datetime,
+                },
+                metadata: RunMetadata::from_reports(&reports),
+                reports,
+                rejected: vec![],
+            };
+            let parsed = RunLog::from_str(&run.to_string()).unwrap();
+            prop_assert_eq!(parsed.reports, run.reports);
+        }
+    }
+
     #[test]
     fn test_display_report() {
-        let report = "@@Common@@result_repaired@@hasPolicyServer-root@@common-root@@0@@CRON Daemon@@None@@2018-08-24 15:55:01 +00:00##root@#Cron daemon status was repaired";
-        //let report = Report::from_str(report).unwrap();
+        let report_str = "@@Common@@result_repaired@@hasPolicyServer-root@@common-root@@0@@CRON Daemon@@None@@2018-08-24 15:55:01+00:00##root@#Cron daemon status was repaired";
+        let report = Report::from_str(report_str).unwrap();
+        assert_eq!(report_str, format!("{:}", report));
         assert_eq!(
             report,
-            format!(
-                "{:}",
-                Report {
-                    start_datetime: DateTime::parse_from_str(
-                        "2018-08-24 15:55:01+00:00",
-                        "%Y-%m-%d %H:%M:%S%z"
-                    )
-                    .unwrap(),
-                    rule_id: "hasPolicyServer-root".into(),
-                    directive_id: "common-root".into(),
-                    component: "CRON Daemon".into(),
-                    key_value: "None".into(),
-                    event_type: "result_repaired".into(),
-                    msg: "Cron daemon status was repaired".into(),
-                    policy: "Common".into(),
-                    node_id: "root".into(),
-                    serial: 0,
-                    execution_datetime: DateTime::parse_from_str(
-                        "2018-08-24 15:55:01+00:00",
-                        "%Y-%m-%d %H:%M:%S%z"
-                    )
-                    .unwrap(),
-                }
-            )
+            Report {
+                start_datetime: DateTime::parse_from_str(
+                    "2018-08-24 15:55:01+00:00",
+                    "%Y-%m-%d %H:%M:%S%z"
+                )
+                .unwrap(),
+                rule_id: "hasPolicyServer-root".into(),
+                directive_id: "common-root".into(),
+                component: "CRON Daemon".into(),
+                key_value: "None".into(),
+                event_type: "result_repaired".into(),
+                msg: "Cron daemon status was repaired".into(),
+                policy: "Common".into(),
+                node_id: "root".into(),
+                serial: 0,
+                execution_datetime: DateTime::parse_from_str(
+                    "2018-08-24 15:55:01+00:00",
+                    "%Y-%m-%d %H:%M:%S%z"
+                )
+                .unwrap(),
+                log_datetime: None,
+            }
         );
+
+        for msg in &["", "repaired\n", "\nrepaired\n\n"] {
+            let report = Report {
+                msg: msg.to_string(),
+                ..report.clone()
+            };
+            assert_eq!(Report::from_str(&report.to_string()).unwrap(), report);
+        }
+
+        let missing_node = "@@Common@@result_repaired@@hasPolicyServer-root@@common-root@@0@@CRON Daemon@@None@@2018-08-24 15:55:01+00:00##root Cron daemon status was repaired";
+        match Report::from_str(missing_node) {
+            Err(Error::InvalidRunLog(e)) => {
+                assert_eq!((e.column, e.expected.as_str()), (112, "@#"))
+            }
+            _ => panic!("report without its node separator should be refused"),
+        }
+        match Report::from_str("Common@@result_repaired") {
+            Err(Error::InvalidRunLog(e)) => assert_eq!(e.expected, "@@"),
+            _ => panic!("report without its prefix should be refused"),
+        }
     }
 
     #[test]
@@ -377,39 +1023,101 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_invalid_runinfo() {
+        assert!(RunInfo::from_str("garbage@root.log").is_err());
+        assert!(RunInfo::from_str("2018-08-24T15:55:01+00:00-root.log").is_err());
+    }
+
+    #[test]
+    fn test_event_type() {
+        assert_eq!(
+            EventType::from("audit_noncompliant"),
+            EventType::AuditNoncompliant
+        );
+        assert_eq!(EventType::from("result_na").to_string(), "result_na");
+        let future = EventType::from("result_future");
+        assert_eq!(future, EventType::Unknown("result_future".to_owned()));
+        assert_eq!(future.as_str(), "result_future");
+        assert_eq!(
+            serde_json::to_string(&EventType::LogWarn).unwrap(),
+            "\"log_warn\""
+        );
+        assert!(EventType::LogTrace.is_log());
+    }
+
     #[test]
     fn test_parse_log_level() {
         assert_eq!(
-            agent_log_level(CompleteStr::from("CRITICAL: toto"))
+            agent_log_level(CompleteStr::from("CRITICAL: toto"), &default_log_prefixes())
                 .unwrap()
                 .1,
-            "log_warn"
+            EventType::LogWarn
         )
     }
 
     #[test]
     fn test_parse_multiline() {
         assert_eq!(
-            simpleline(CompleteStr::from("The thing\n")).unwrap().1,
-            "The thing".to_string()
+            simpleline(CompleteStr::from("The thing\n"), &default_log_prefixes())
+                .unwrap()
+                .1,
+            CompleteStr("The thing")
         );
         assert_eq!(
-            simpleline(CompleteStr::from("The thing\nR: report"))
+            simpleline(
+                CompleteStr::from("The thing\nR: report"),
+                &default_log_prefixes()
+            )
+            .unwrap()
+            .1,
+            CompleteStr("The thing")
+        );
+        assert!(simpleline(
+            CompleteStr::from("R: The thing\nreport"),
+            &default_log_prefixes()
+        )
+        .is_err());
+        assert!(simpleline(
+            CompleteStr::from("CRITICAL: plop\nreport"),
+            &default_log_prefixes()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_custom_log_prefixes() {
+        let prefixes = vec![LogPrefix::new("NOTICE:", EventType::LogRepaired)];
+        assert_eq!(
+            agent_log_level(CompleteStr::from("NOTICE: toto"), &prefixes)
+                .unwrap()
+                .1,
+            EventType::LogRepaired
+        );
+        // Unknown prefixes are not log entries
+        assert!(agent_log_level(CompleteStr::from("CRITICAL: toto"), &prefixes).is_err());
+        // Untagged reports are still logs
+        assert_eq!(
+            agent_log_level(CompleteStr::from("R: toto"), &prefixes)
                 .unwrap()
                 .1,
-            "The thing".to_string()
+            EventType::LogInfo
         );
-        assert!(simpleline(CompleteStr::from("R: The thing\nreport")).is_err());
-        assert!(simpleline(CompleteStr::from("CRITICAL: plop\nreport")).is_err());
     }
 
     #[test]
     fn test_parse_log_entry() {
         assert_eq!(
-            log_entry(CompleteStr::from("CRITICAL: toto\n")).unwrap().1,
+            log_entry(
+                CompleteStr::from("CRITICAL: toto\n"),
+                &default_log_prefixes()
+            )
+            .unwrap()
+            .1,
             LogEntry {
-                event_type: "log_warn",
-                msg: "toto".to_string(),
+                log_datetime: None,
+                event_type: EventType::LogWarn,
+                msg: "toto",
             }
         )
     }
@@ -417,22 +1125,231 @@ mod tests {
     #[test]
     fn test_parse_log_entries() {
         assert_eq!(
-            log_entries(CompleteStr::from("CRITICAL: toto\nsuite\nCRITICAL: tutu\n"))
-                .unwrap()
-                .1,
+            log_entries(
+                CompleteStr::from(
+                    "CRITICAL: toto\nsuite\n2018-12-27 19:35:38+00:00 CRITICAL: tutu\n"
+                ),
+                &default_log_prefixes()
+            )
+            .unwrap()
+            .1,
             vec![
                 LogEntry {
-                    event_type: "log_warn",
-                    msg: "toto\nsuite".to_string(),
+                    log_datetime: None,
+                    event_type: EventType::LogWarn,
+                    msg: "toto\nsuite",
                 },
                 LogEntry {
-                    event_type: "log_warn",
-                    msg: "tutu".to_string()
+                    log_datetime: Some(
+                        DateTime::parse_from_str(
+                            "2018-12-27 19:35:38+00:00",
+                            "%Y-%m-%d %H:%M:%S%z"
+                        )
+                        .unwrap()
+                    ),
+                    event_type: EventType::LogWarn,
+                    msg: "tutu"
                 }
             ]
         )
     }
 
+    #[test]
+    fn test_parse_runlog_envelope() {
+        let run_log = &read_to_string("tests/runlogs/2019-01-24T15:55:01+00:00@root.log").unwrap();
+        let envelope = RunLogEnvelope::parse(run_log).unwrap();
+        assert_eq!(
+            envelope.header,
+            Some(RunLogHeader {
+                format: 0,
+                node_id: "b01069b6-415c-4ec1-b3f2-ccb8ef759da6".into(),
+                timestamp: DateTime::parse_from_str("2019-01-04T11:40:13+01:00", "%+").unwrap(),
+                key_hash: "kfdjsmfjksdljf14DLVdf02".into(),
+                signature: "FSFOSDJFSDLDS?FLDSF?LDS?FDS".into(),
+            })
+        );
+        assert!(envelope
+            .data
+            .starts_with("2018-12-27 19:35:38+00:00 R: @@Common@@result_success"));
+        assert!(envelope.data.ends_with("##root@#End execution\n"));
+
+        let legacy = "R: @@Common@@control@@rudder@@run@@0@@end@@20180824-130007-3ad37587@@2018-08-24 15:55:01+00:00##root@#End execution\n";
+        let envelope = RunLogEnvelope::parse(legacy).unwrap();
+        assert_eq!(envelope.header, None);
+        assert_eq!(envelope.data, legacy);
+
+        let crlf = "format=0\r\nnodeid=\"root\"\r\nruntimestamp=\"2018-08-24T15:55:01+00:00\"\r\nkeyhash=\"sha256:abc\"\r\nsignature=\"def\"\r\n---- data ----\r\nR: @@Common@@control@@rudder@@run@@0@@end@@20180824-130007-3ad37587@@2018-08-24 15:55:01+00:00##root@#End execution\r\n---- end data ----\r\n";
+        let envelope = RunLogEnvelope::parse(crlf).unwrap();
+        assert_eq!(envelope.header.unwrap().node_id, "root");
+        assert_eq!(envelope.data_line, 7);
+        let run = RunLog::from_str(crlf).unwrap();
+        assert_eq!(run.reports[0].msg, "End execution");
+
+        let truncated = "format=0\n---- data ----\nR: @@Common@@control";
+        match RunLogEnvelope::parse(truncated) {
+            Err(Error::InvalidRunLog(e)) => {
+                assert_eq!((e.line, e.expected.as_str()), (3, DATA_END));
+                assert_eq!(e.snippet, "R: @@Common@@control");
+            }
+            _ => panic!("truncated runlog should be refused"),
+        }
+    }
+
+    #[test]
+    fn test_parse_enveloped_runlog() {
+        let run_log = "format=0\nnodeid=\"root\"\nruntimestamp=\"2018-08-24T15:55:01+00:00\"\nkeyhash=\"sha256:abc\"\nsignature=\"def\"\n---- data ----\nR: @@Common@@control@@rudder@@run@@0@@end@@20180824-130007-3ad37587@@2018-08-24 15:55:01+00:00##root@#End execution\n---- end data ----\nignored\n";
+        let run = RunLog::from_str(run_log).unwrap();
+        assert_eq!(run.header.unwrap().node_id, "root".to_owned());
+        assert_eq!(run.reports.len(), 1);
+        assert_eq!(run.reports[0].msg, "End execution".to_owned());
+
+        let unsupported = run_log.replace("format=0", "format=42");
+        match RunLog::from_str(&unsupported) {
+            Err(Error::UnsupportedRunLogFormat(42)) => (),
+            _ => panic!("format 42 should not be supported"),
+        }
+
+        let broken = run_log.replace("+00:00##root", "+00:00 root");
+        match RunLog::from_str(&broken) {
+            Err(Error::InvalidRunLog(e)) => {
+                assert_eq!(e.line, 7);
+                assert_eq!(e.column, 70);
+                assert!(e.snippet.starts_with("R: @@Common@@control"));
+            }
+            _ => panic!("broken report should be refused"),
+        }
+    }
+
+    #[test]
+    fn test_validate_runlog() {
+        let nodes =
+            parse_nodeslist(&read_to_string("tests/files/nodeslist.json").unwrap()).unwrap();
+        let run_log = &read_to_string("tests/runlogs/2018-08-24T15:55:01+00:00@root.log").unwrap();
+        let run = RunLog::from_str(run_log).unwrap();
+
+        let info = RunInfo::from_str("2018-08-24T15:55:01+00:00@root.log").unwrap();
+        assert!(run.validate(&info, &nodes).is_ok());
+
+        let other_node =
+            RunInfo::from_str("2018-08-24T15:55:01+00:00@0636e494-8da7-4f86-ad4b-eb99ac08b4a3.log")
+                .unwrap();
+        match run.validate(&other_node, &nodes) {
+            Err(Error::InconsistentRunlog(_)) => (),
+            _ => panic!("runlog from another node should be refused"),
+        }
+
+        let other_run = RunInfo::from_str("2018-08-24T16:55:01+00:00@root.log").unwrap();
+        match run.validate(&other_run, &nodes) {
+            Err(Error::InconsistentRunlog(_)) => (),
+            _ => panic!("runlog with another timestamp should be refused"),
+        }
+
+        let unknown = RunInfo::from_str("2018-08-24T15:55:01+00:00@unknown.log").unwrap();
+        match run.validate(&unknown, &nodes) {
+            Err(Error::UnknownNode(_)) => (),
+            _ => panic!("runlog from an unknown node should be refused"),
+        }
+
+        // The header is not signed, changing it along with the file name is detected
+        let run_log = "format=0\nnodeid=\"root\"\nruntimestamp=\"2018-08-24T16:55:01+00:00\"\nkeyhash=\"sha256:abc\"\nsignature=\"def\"\n---- data ----\nR: @@Common@@control@@rudder@@run@@0@@end@@20180824-130007-3ad37587@@2018-08-24 15:55:01+00:00##root@#End execution\n---- end data ----\n";
+        let run = RunLog::from_str(run_log).unwrap();
+        match run.validate(&other_run, &nodes) {
+            Err(Error::InconsistentRunlog(_)) => (),
+            _ => panic!("runlog with a tampered header should be refused"),
+        }
+    }
+
+    #[test]
+    fn test_parse_corrupt_runlog() {
+        let valid = "R: @@Common@@control@@rudder@@run@@0@@start@@20180824-130007-3ad37587@@2018-08-24 15:55:01+00:00##root@#Start execution\n";
+        let bad_serial = "R: @@Common@@control@@rudder@@run@@zero@@end@@20180824-130007-3ad37587@@2018-08-24 15:55:01+00:00##root@#Bad serial\nwith a second line\n";
+        let bad_date = "R: @@Common@@control@@rudder@@run@@0@@end@@20180824-130007-3ad37587@@yesterday##root@#Bad date\n";
+        let run_log = format!("{}{}{}{}", valid, bad_serial, valid, bad_date);
+        let envelope = RunLogEnvelope::parse(&run_log).unwrap();
+
+        let run = envelope
+            .runlog(ParsingMode::Lenient, &default_log_prefixes())
+            .unwrap();
+        assert_eq!(run.reports.len(), 2);
+        assert_eq!(run.rejected.len(), 2);
+        assert_eq!(run.rejected[0].error.line, 2);
+        assert_eq!(run.rejected[0].error.expected, "serial");
+        assert_eq!(run.rejected[0].content, bad_serial.trim_end());
+        assert_eq!(run.rejected[1].error.line, 5);
+        assert_eq!(run.rejected[1].error.expected, "datetime");
+
+        match envelope.runlog(ParsingMode::Strict, &default_log_prefixes()) {
+            Err(Error::InvalidRunLog(e)) => assert_eq!(e.line, 2),
+            _ => panic!("strict parsing should refuse the runlog"),
+        }
+        match RunLogEnvelope::parse(bad_date)
+            .unwrap()
+            .runlog(ParsingMode::Lenient, &default_log_prefixes())
+        {
+            Err(Error::InvalidRunLog(_)) => (),
+            _ => panic!("runlog without valid reports should be refused"),
+        }
+        match RunInfo::from_str("yesterday@root.log") {
+            Err(Error::InvalidRunInfo(e)) => assert_eq!(e.expected, "datetime"),
+            _ => panic!("invalid timestamp should be refused"),
+        }
+        match RunInfo::from_str("2018-08-24T15:55:01+00:00@root") {
+            Err(Error::InvalidRunInfo(e)) => assert_eq!(e.expected, ".log"),
+            _ => panic!("missing extension should be refused"),
+        }
+    }
+
+    #[test]
+    fn test_parse_timestamped_runlog() {
+        let run_log = &read_to_string("tests/runlogs/2019-01-24T15:55:01+00:00@root.log").unwrap();
+        let run = RunLogEnvelope::parse(run_log)
+            .unwrap()
+            .runlog(ParsingMode::Lenient, &default_log_prefixes())
+            .unwrap();
+        assert_eq!(
+            run.reports[0].log_datetime,
+            Some(
+                DateTime::parse_from_str("2018-12-27 19:35:38+00:00", "%Y-%m-%d %H:%M:%S%z")
+                    .unwrap()
+            )
+        );
+        assert_eq!(
+            run.reports[0].msg,
+            "Cron daemon status was correct".to_owned()
+        );
+        assert_eq!(run.reports.last().unwrap().msg, "End execution".to_owned());
+        // Second report is missing its "##" separator
+        assert_eq!(run.rejected[0].error.line, 10);
+        assert_eq!(run.rejected[0].error.expected, "##");
+    }
+
+    #[test]
+    fn test_run_metadata() {
+        let run_log = &read_to_string("tests/runlogs/2018-08-24T15:55:01+00:00@root.log").unwrap();
+        let run = RunLog::from_str(run_log).unwrap();
+        assert_eq!(
+            run.metadata,
+            RunMetadata {
+                config_id: Some("20180824-130007-3ad37587".to_owned()),
+                complete: true,
+                agent_version: None,
+                capabilities: vec![],
+            }
+        );
+
+        let interrupted = "R: @@Common@@control@@rudder@@run@@0@@start@@20180824-130007-3ad37587@@2018-08-24 15:55:01+00:00##root@#Start execution\nR: @@Common@@control@@rudder@@run@@0@@agent_version@@5.0.6@@2018-08-24 15:55:01+00:00##root@#Agent version\nR: @@Common@@control@@rudder@@run@@0@@capabilities@@cfengine, xml,acl@@2018-08-24 15:55:01+00:00##root@#Agent capabilities\n";
+        let run = RunLog::from_str(interrupted).unwrap();
+        assert_eq!(
+            run.metadata,
+            RunMetadata {
+                config_id: Some("20180824-130007-3ad37587".to_owned()),
+                complete: false,
+                agent_version: Some("5.0.6".to_owned()),
+                capabilities: vec!["cfengine".to_owned(), "xml".to_owned(), "acl".to_owned()],
+            }
+        );
+    }
+
     #[test]
     fn test_parse_runlog() {
         let run_log = &read_to_string("tests/runlogs/2018-08-24T15:55:01+00:00@root.log").unwrap();
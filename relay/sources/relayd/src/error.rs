@@ -29,25 +29,52 @@
 // along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
 
 use self::Error::*;
+use crate::{data::nodes::NodeId, output::database::check::SchemaIssue};
 use chrono;
 use diesel;
+use hyper;
+use nom::{types::CompleteStr, Context, ErrorKind};
+use openssl;
 use serde_json;
 use std::{
     error::Error as StdError,
     fmt::{self, Display, Formatter},
     io, num,
+    path::{Path, PathBuf},
 };
+use tokio::timer;
 use toml;
 
 /// An enum of all error kinds.
 #[derive(Debug)]
 pub enum Error {
-    /// Report parsing
-    /// Unspecified nom parsing error
-    /// TODO add details
-    InvalidRunLog,
-    InvalidRunInfo,
+    /// Runlog content could not be parsed
+    InvalidRunLog(ParseError),
+    /// Runlog file name could not be parsed
+    InvalidRunInfo(ParseError),
+    /// Received file path has no valid UTF-8 file name
+    InvalidFileName(PathBuf),
     EmptyRunlog,
+    /// Runlog header announces a format we do not know how to parse
+    UnsupportedRunLogFormat(u32),
+    /// Runlog content does not match its file name or the nodes list
+    InconsistentRunlog(String),
+    /// Runlog has no signature header
+    MissingSignature,
+    /// Node is not in the nodes list
+    UnknownNode(NodeId),
+    /// Key hash does not match the one in the nodes list
+    KeyHashMismatch(NodeId),
+    /// Signature does not match the content
+    InvalidSignature(NodeId),
+    /// Compressed file could not be decompressed
+    InvalidCompressedFile(String),
+    /// Decompressed file exceeds the configured limit
+    DecompressedFileTooLarge(u64),
+    /// Inventory is not a complete OCS inventory
+    InvalidInventory,
+    /// Cryptographic error
+    Ssl(openssl::error::ErrorStack),
     /// Internal client error
     Message(String),
     /// Database error
@@ -56,6 +83,8 @@ pub enum Error {
     DatabaseConnection(diesel::ConnectionError),
     /// Connection pool error
     Pool(diesel::r2d2::PoolError),
+    /// Database schema or privileges do not allow inserting reports
+    IncompatibleDatabase(Vec<SchemaIssue>),
     /// IO error
     Io(io::Error),
     /// TOML error
@@ -68,28 +97,263 @@ pub enum Error {
     IntegerParsing(num::ParseIntError),
     /// UTF-8 parsing
     Utf8(std::string::FromUtf8Error),
+    /// HTTP client error
+    Http(hyper::Error),
+    /// Upstream server refused the upload
+    HttpStatus(hyper::StatusCode),
+    /// Upstream server did not answer in time
+    UploadTimeout,
+    /// Upstream TLS options are inconsistent
+    InvalidUpstreamTls(String),
+    /// Timer error
+    Timer(timer::Error),
 }
 
 impl Display for Error {
     fn fmt(&self, fmt: &mut Formatter<'_>) -> Result<(), fmt::Error> {
         fmt.write_str(&match *self {
-            InvalidRunLog => "invalid run log".to_owned(),
-            InvalidRunInfo => "invalid run info".to_owned(),
+            InvalidRunLog(ref err) => format!("invalid run log: {}", err),
+            InvalidRunInfo(ref err) => format!("invalid run info: {}", err),
+            InvalidFileName(ref path) => format!("invalid file name in {:?}", path),
             EmptyRunlog => "agent run log is empty".to_owned(),
+            UnsupportedRunLogFormat(format) => format!("unsupported run log format {}", format),
+            InconsistentRunlog(ref reason) => format!("inconsistent run log: {}", reason),
+            MissingSignature => "run log is not signed".to_owned(),
+            UnknownNode(ref node) => format!("unknown node {}", node),
+            KeyHashMismatch(ref node) => format!("key hash does not match for node {}", node),
+            InvalidSignature(ref node) => format!("invalid run log signature for node {}", node),
+            InvalidCompressedFile(ref err) => format!("invalid compressed file: {}", err),
+            DecompressedFileTooLarge(limit) => {
+                format!("file content is larger than {} bytes", limit)
+            }
+            InvalidInventory => "invalid inventory".to_owned(),
+            Ssl(ref err) => err.to_string(),
+            Http(ref err) => err.to_string(),
+            HttpStatus(status) => format!("upstream server answered {}", status),
+            UploadTimeout => "upload timed out".to_owned(),
+            InvalidUpstreamTls(ref reason) => {
+                format!("invalid upstream TLS configuration: {}", reason)
+            }
             Message(ref message) => message.clone(),
             Database(ref err) => err.to_string(),
             DatabaseConnection(ref err) => err.to_string(),
             Pool(ref err) => err.to_string(),
+            IncompatibleDatabase(ref issues) => format!(
+                "incompatible database: {}",
+                issues
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            ),
             Io(ref err) => err.to_string(),
             Toml(ref err) => err.to_string(),
             DateParsing(ref err) => err.to_string(),
             JsonParsing(ref err) => err.to_string(),
             IntegerParsing(ref err) => err.to_string(),
             Utf8(ref err) => err.to_string(),
+            Timer(ref err) => err.to_string(),
         })
     }
 }
 
+impl Error {
+    /// Adds the name of the file being parsed to parsing errors
+    pub fn in_file(self, file: &Path) -> Self {
+        match self {
+            InvalidRunLog(err) => InvalidRunLog(err.in_file(file)),
+            InvalidRunInfo(err) => InvalidRunInfo(err.in_file(file)),
+            err => err,
+        }
+    }
+
+    /// Errors caused by the database not being reachable, and not by the inserted data,
+    /// the same insertion can succeed once the database is back
+    pub fn is_database_unavailable(&self) -> bool {
+        match *self {
+            Pool(_) | DatabaseConnection(_) => true,
+            Database(diesel::result::Error::DatabaseError(
+                diesel::result::DatabaseErrorKind::UnableToSendCommand,
+                _,
+            )) => true,
+            _ => false,
+        }
+    }
+
+    /// Upload errors caused by the network or the upstream server, and not by the sent file
+    pub fn is_upstream_unavailable(&self) -> bool {
+        match *self {
+            Http(ref err) => err.is_connect() || err.is_closed() || err.is_canceled(),
+            HttpStatus(status) => status.is_server_error(),
+            UploadTimeout => true,
+            _ => false,
+        }
+    }
+
+    /// Upstream server refused the upload, uploading it again would fail the same way.
+    /// Client errors are permanent, except timeouts and rate limiting.
+    pub fn is_upload_refused(&self) -> bool {
+        match *self {
+            HttpStatus(status) => {
+                status.is_client_error()
+                    && status != hyper::StatusCode::REQUEST_TIMEOUT
+                    && status != hyper::StatusCode::TOO_MANY_REQUESTS
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Maximum length of the line excerpt kept in parsing errors
+const SNIPPET_LENGTH: usize = 80;
+
+/// Tokens and fields missing from the parsed input, passed to nom as custom error codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expected {
+    /// Separator between report fields
+    FieldSeparator,
+    /// Separator after the report start date
+    DateSeparator,
+    /// Separator after the report node id
+    NodeSeparator,
+    /// Beginning of a Rudder report
+    ReportBegin,
+    /// Numeric serial
+    Serial,
+    /// Date in reports or file names
+    Datetime,
+    /// Separator after the timestamp in runlog names
+    TimestampSeparator,
+    /// Extension of runlog names
+    LogExtension,
+}
+
+impl Expected {
+    const ALL: [Expected; 8] = [
+        Expected::FieldSeparator,
+        Expected::DateSeparator,
+        Expected::NodeSeparator,
+        Expected::ReportBegin,
+        Expected::Serial,
+        Expected::Datetime,
+        Expected::TimestampSeparator,
+        Expected::LogExtension,
+    ];
+
+    pub fn code(self) -> ErrorKind {
+        ErrorKind::Custom(self as u32)
+    }
+
+    fn from_code(code: u32) -> Option<Self> {
+        Self::ALL.get(code as usize).cloned()
+    }
+
+    pub fn token(self) -> &'static str {
+        match self {
+            Expected::FieldSeparator => "@@",
+            Expected::DateSeparator => "##",
+            Expected::NodeSeparator => "@#",
+            Expected::ReportBegin => "R: @@",
+            Expected::Serial => "serial",
+            Expected::Datetime => "datetime",
+            Expected::TimestampSeparator => "@",
+            Expected::LogExtension => ".log",
+        }
+    }
+}
+
+/// Location and cause of a parsing failure
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Parsed file, when known
+    pub file: Option<PathBuf>,
+    /// Line of the failure, starting at 1
+    pub line: usize,
+    /// Column of the failure in characters, starting at 1
+    pub column: usize,
+    /// What the parser was expecting at this position
+    pub expected: String,
+    /// Beginning of the offending line
+    pub snippet: String,
+}
+
+impl ParseError {
+    /// Builds an error located at `position`, which should be a sub-slice of `input`
+    pub fn new(input: &str, position: &str, expected: &str) -> Self {
+        // Locate the error at the end of the input rather than panic on an invalid position
+        let mut offset = (position.as_ptr() as usize)
+            .checked_sub(input.as_ptr() as usize)
+            .unwrap_or(input.len())
+            .min(input.len());
+        while !input.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        let before = &input[..offset];
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+
+        ParseError {
+            file: None,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            expected: expected.to_owned(),
+            snippet: input[line_start..]
+                .lines()
+                .next()
+                .unwrap_or("")
+                .chars()
+                .take(SNIPPET_LENGTH)
+                .collect(),
+        }
+    }
+
+    /// Locates a nom error in the parsed input
+    pub fn from_nom(input: &str, err: nom::Err<CompleteStr<'_>>) -> Self {
+        match err {
+            nom::Err::Error(Context::Code(position, kind))
+            | nom::Err::Failure(Context::Code(position, kind)) => {
+                let expected = match kind {
+                    ErrorKind::Custom(code) => Expected::from_code(code).map(Expected::token),
+                    _ => None,
+                };
+                Self::new(
+                    input,
+                    position.0,
+                    expected.unwrap_or_else(|| kind.description()),
+                )
+            }
+            nom::Err::Incomplete(_) => Self::new(input, &input[input.len()..], "more data"),
+        }
+    }
+
+    pub fn in_file(self, file: &Path) -> Self {
+        ParseError {
+            file: Some(file.to_path_buf()),
+            ..self
+        }
+    }
+
+    /// Shifts the location when the parsed input does not start at the beginning of the file
+    pub fn starting_at_line(self, line: usize) -> Self {
+        ParseError {
+            line: self.line + line - 1,
+            ..self
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        if let Some(ref file) = self.file {
+            write!(fmt, "{}:", file.display())?;
+        }
+        write!(
+            fmt,
+            "{}:{}: expected {}, found {:?}",
+            self.line, self.column, self.expected, self.snippet
+        )
+    }
+}
+
 impl StdError for Error {
     fn cause(&self) -> Option<&dyn StdError> {
         match *self {
@@ -102,6 +366,9 @@ impl StdError for Error {
             JsonParsing(ref err) => Some(err),
             IntegerParsing(ref err) => Some(err),
             Utf8(ref err) => Some(err),
+            Ssl(ref err) => Some(err),
+            Http(ref err) => Some(err),
+            Timer(ref err) => Some(err),
             _ => None,
         }
     }
@@ -166,3 +433,62 @@ impl From<std::string::FromUtf8Error> for Error {
         Error::Utf8(err)
     }
 }
+
+impl From<openssl::error::ErrorStack> for Error {
+    fn from(err: openssl::error::ErrorStack) -> Error {
+        Error::Ssl(err)
+    }
+}
+
+impl From<hyper::Error> for Error {
+    fn from(err: hyper::Error) -> Error {
+        Error::Http(err)
+    }
+}
+
+impl From<timer::Error> for Error {
+    fn from(err: timer::Error) -> Error {
+        Error::Timer(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_locates_parse_errors() {
+        let input = "first line\nsecond line\nthird line";
+        let err = ParseError::new(input, &input[18..], "report");
+        assert_eq!(err.line, 2);
+        assert_eq!(err.column, 8);
+        assert_eq!(err.snippet, "second line");
+        assert_eq!(
+            err.in_file(Path::new("run.log")).to_string(),
+            "run.log:2:8: expected report, found \"second line\""
+        );
+
+        let end = ParseError::new(input, &input[input.len()..], "more data");
+        assert_eq!((end.line, end.column), (3, 11));
+        assert_eq!(end.starting_at_line(5).line, 7);
+
+        // Positions outside of the input are located at its end
+        let outside = ParseError::new(&input[11..], input, "report");
+        assert_eq!((outside.line, outside.column), (2, 11));
+    }
+
+    #[test]
+    fn it_detects_database_unavailability() {
+        assert!(DatabaseConnection(diesel::ConnectionError::BadConnection(
+            "refused".to_string()
+        ))
+        .is_database_unavailable());
+        assert!(Database(diesel::result::Error::DatabaseError(
+            diesel::result::DatabaseErrorKind::UnableToSendCommand,
+            Box::new("server closed the connection".to_string()),
+        ))
+        .is_database_unavailable());
+        assert!(!Database(diesel::result::Error::NotFound).is_database_unavailable());
+        assert!(!EmptyRunlog.is_database_unavailable());
+    }
+}
@@ -30,7 +30,7 @@
 
 use crate::data::{
     nodes::NodeId,
-    reporting::{Report, RunInfo, RunLog},
+    reporting::{EventType, Report, RunInfo, RunLog, RunMetadata},
 };
 use chrono::DateTime;
 use rand::{self, Rng};
@@ -52,12 +52,13 @@ pub fn runlog(node_id: Option<NodeId>) -> RunLog {
         directive_id: "run".to_string(),
         component: "start".to_string(),
         key_value: "20180824-130007-3ad37587".to_string(),
-        event_type: "control".to_string(),
+        event_type: EventType::Control,
         msg: "Start execution".to_string(),
         policy: "Common".to_string(),
         node_id: node_id.clone(),
         execution_datetime: timestamp,
         serial: 0,
+        log_datetime: None,
     };
     let end = Report {
         start_datetime: timestamp,
@@ -65,12 +66,13 @@ pub fn runlog(node_id: Option<NodeId>) -> RunLog {
         directive_id: "run".to_string(),
         component: "end".to_string(),
         key_value: "20180824-130007-3ad37587".to_string(),
-        event_type: "control".to_string(),
+        event_type: EventType::Control,
         msg: "End execution".to_string(),
         policy: "Common".to_string(),
         node_id: node_id.clone(),
         execution_datetime: timestamp,
         serial: 0,
+        log_datetime: None,
     };
 
     reports.push(begin);
@@ -82,19 +84,23 @@ pub fn runlog(node_id: Option<NodeId>) -> RunLog {
             directive_id: Uuid::new_v4().to_string(),
             component: "test".to_string(),
             key_value: "test".to_string(),
-            event_type: "result_repaired".to_string(),
+            event_type: EventType::ResultRepaired,
             msg: "test".to_string(),
             policy: "rule".to_string(),
             node_id: node_id.clone(),
             execution_datetime: timestamp,
             serial: 0,
+            log_datetime: None,
         });
     }
 
     reports.push(end);
 
     RunLog {
+        header: None,
         info: RunInfo { node_id, timestamp },
+        metadata: RunMetadata::from_reports(&reports),
         reports,
+        rejected: vec![],
     }
 }
@@ -0,0 +1,200 @@
+// Copyright 2019 Normation SAS
+//
+// This file is part of Rudder.
+//
+// Rudder is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In accordance with the terms of section 7 (7. Additional Terms.) of
+// the GNU General Public License version 3, the copyright holders add
+// the following Additional permissions:
+// Notwithstanding to the terms of section 5 (5. Conveying Modified Source
+// Versions) and 6 (6. Conveying Non-Source Forms.) of the GNU General
+// Public License version 3, when you create a Related Module, this
+// Related Module is not considered as a part of the work and may be
+// distributed under the license agreement of your choice.
+// A "Related Module" means a set of sources files including their
+// documentation that, without modification of the Source Code, enables
+// supplementary functions or services in addition to those offered by
+// the Software.
+//
+// Rudder is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::error::Error;
+use flate2::read::GzDecoder;
+use std::{io::Read, path::Path};
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+/// Extensions of the compressed files, with their dot
+const EXTENSIONS: &[&str] = &[".gz", ".zst"];
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    fn from_extension(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+
+    fn from_magic(content: &[u8]) -> Self {
+        if content.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if content.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Magic bytes are authoritative, but a file announcing a compression
+    /// in its name must actually use it.
+    pub fn detect(path: &Path, content: &[u8]) -> Result<Self, Error> {
+        let by_magic = Self::from_magic(content);
+        let by_extension = Self::from_extension(path);
+        if by_extension != Compression::None && by_extension != by_magic {
+            Err(Error::InvalidCompressedFile(format!(
+                "{:?} does not look like a {:?} file",
+                path, by_extension
+            )))
+        } else {
+            Ok(by_magic)
+        }
+    }
+}
+
+/// Returns the name of the file before compression, `node.ocs` for `node.ocs.gz`
+pub fn uncompressed_name(name: &str) -> &str {
+    EXTENSIONS
+        .iter()
+        .find(|extension| name.ends_with(*extension))
+        .map(|extension| &name[..name.len() - extension.len()])
+        .unwrap_or(name)
+}
+
+/// Returns the possible names of a file once compressed, itself included
+pub fn compressed_names(name: &str) -> Vec<String> {
+    let mut names = vec![name.to_string()];
+    names.extend(
+        EXTENSIONS
+            .iter()
+            .map(|extension| format!("{}{}", name, extension)),
+    );
+    names
+}
+
+/// Reads at most `limit` bytes, and fails if there is more to protect against decompression bombs
+fn read_limited<R: Read>(reader: R, limit: u64) -> Result<Vec<u8>, Error> {
+    let mut res = vec![];
+    reader
+        // One more byte to know if we reached the limit
+        .take(limit + 1)
+        .read_to_end(&mut res)
+        .map_err(|e| Error::InvalidCompressedFile(e.to_string()))?;
+    if res.len() as u64 > limit {
+        Err(Error::DecompressedFileTooLarge(limit))
+    } else {
+        Ok(res)
+    }
+}
+
+/// Returns the uncompressed content of a received file, compressed or not
+pub fn decompress(path: &Path, content: Vec<u8>, limit: u64) -> Result<Vec<u8>, Error> {
+    match Compression::detect(path, &content)? {
+        Compression::None if content.len() as u64 > limit => {
+            Err(Error::DecompressedFileTooLarge(limit))
+        }
+        Compression::None => Ok(content),
+        Compression::Gzip => read_limited(GzDecoder::new(&content[..]), limit),
+        Compression::Zstd => read_limited(
+            ZstdDecoder::new(&content[..])
+                .map_err(|e| Error::InvalidCompressedFile(e.to_string()))?,
+            limit,
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression as GzLevel};
+    use std::io::Write;
+
+    fn gzip(content: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), GzLevel::default());
+        encoder.write_all(content).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn it_detects_compression() {
+        let gz = gzip(b"R: report\n");
+        let zst = zstd::stream::encode_all(&b"R: report\n"[..], 0).unwrap();
+        assert_eq!(
+            Compression::detect(Path::new("run.log.gz"), &gz).unwrap(),
+            Compression::Gzip
+        );
+        assert_eq!(
+            Compression::detect(Path::new("run.log.zst"), &zst).unwrap(),
+            Compression::Zstd
+        );
+        assert_eq!(
+            Compression::detect(Path::new("run.log"), b"R: report\n").unwrap(),
+            Compression::None
+        );
+        assert!(Compression::detect(Path::new("run.log.gz"), b"R: report\n").is_err());
+    }
+
+    #[test]
+    fn it_decompresses_files() {
+        let content = b"R: report\n".to_vec();
+        assert_eq!(
+            decompress(Path::new("run.log.gz"), gzip(&content), 1024).unwrap(),
+            content
+        );
+        let zst = zstd::stream::encode_all(&content[..], 0).unwrap();
+        assert_eq!(
+            decompress(Path::new("run.log.zst"), zst, 1024).unwrap(),
+            content
+        );
+        assert_eq!(
+            decompress(Path::new("run.log"), content.clone(), 1024).unwrap(),
+            content
+        );
+    }
+
+    #[test]
+    fn it_refuses_invalid_archives() {
+        let bomb = gzip(&[0; 1_000_000]);
+        match decompress(Path::new("run.log.gz"), bomb, 1_000) {
+            Err(Error::DecompressedFileTooLarge(1_000)) => (),
+            _ => panic!("decompression limit was not enforced"),
+        }
+
+        match decompress(Path::new("run.log"), vec![0; 2_000], 1_000) {
+            Err(Error::DecompressedFileTooLarge(1_000)) => (),
+            _ => panic!("size limit was not enforced on uncompressed files"),
+        }
+
+        let mut corrupt = gzip(b"R: report\n");
+        corrupt.truncate(12);
+        assert!(decompress(Path::new("run.log.gz"), corrupt, 1024).is_err());
+    }
+}
@@ -0,0 +1,586 @@
+// Copyright 2019 Normation SAS
+//
+// This file is part of Rudder.
+//
+// Rudder is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In accordance with the terms of section 7 (7. Additional Terms.) of
+// the GNU General Public License version 3, the copyright holders add
+// the following Additional permissions:
+// Notwithstanding to the terms of section 5 (5. Conveying Modified Source
+// Versions) and 6 (6. Conveying Non-Source Forms.) of the GNU General
+// Public License version 3, when you create a Related Module, this
+// Related Module is not considered as a part of the work and may be
+// distributed under the license agreement of your choice.
+// A "Related Module" means a set of sources files including their
+// documentation that, without modification of the Source Code, enables
+// supplementary functions or services in addition to those offered by
+// the Software.
+//
+// Rudder is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
+
+pub mod compression;
+
+use crate::{
+    configuration::{BaseDirectory, CatchupConfig, RetentionConfig, WatchedDirectory},
+    error::Error,
+    stats::{send_stat, Event},
+};
+use futures::{
+    future::{self, Either, Future},
+    stream,
+    sync::mpsc,
+    Stream,
+};
+use inotify::{Inotify, WatchMask};
+use slog::{slog_debug, slog_info, slog_warn};
+use slog_scope::{debug, info, warn};
+use std::{
+    fs::{create_dir_all, metadata, read_dir as std_read_dir, rename as std_rename},
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+    fs::{file::File, metadata as async_metadata, read_dir, remove_file, rename, write},
+    prelude::*,
+    timer::Interval,
+};
+
+pub type ReceivedFile = PathBuf;
+
+/// Sending side of a processing queue, counting queued files in statistics
+#[derive(Clone)]
+pub struct QueueSender {
+    tx: mpsc::Sender<ReceivedFile>,
+    stats: mpsc::Sender<Event>,
+    /// Event to send for each queued file
+    queued: Event,
+}
+
+impl QueueSender {
+    pub fn new(tx: mpsc::Sender<ReceivedFile>, stats: mpsc::Sender<Event>, queued: Event) -> Self {
+        Self { tx, stats, queued }
+    }
+
+    /// Waits for room in the queue, which gives backpressure to the watchers.
+    ///
+    /// The file is counted before being sent, as a worker can take it and count it as
+    /// started before the sending future completes.
+    pub fn send(&self, file: ReceivedFile) -> impl Future<Item = (), Error = ()> {
+        let tx = self.tx.clone();
+        send_stat(self.stats.clone(), self.queued).and_then(move |_| {
+            tx.send(file)
+                .map(|_| ())
+                .map_err(|e| warn!("queue send error: {}", e; "component" => "watcher"))
+        })
+    }
+}
+
+/// Where files are moved while being treated
+pub const PROCESSING_DIRECTORY: &str = "processing";
+/// Where successfully treated files are kept during retention
+pub const PROCESSED_DIRECTORY: &str = "processed";
+/// Where files we could not treat are kept during retention
+pub const FAILED_DIRECTORY: &str = "failed";
+/// Where files wait for an unavailable output to come back
+pub const SPOOL_DIRECTORY: &str = "spool";
+/// Files listed by catch-up need to be older than this to avoid partially written files
+pub const CATCHUP_DELAY: Duration = Duration::from_secs(30);
+/// Interval between two runs of the retention cleaner
+const CLEANUP_FREQUENCY: Duration = Duration::from_secs(60 * 60);
+
+/// Returns the directory where files received in `path` are moved while being treated.
+///
+/// Watched directories are direct children of the base directory, and their claimed files
+/// go to `<base>/processing/<watched directory name>`.
+fn processing_directory(path: &Path) -> PathBuf {
+    let name = path.file_name().expect("watched directory has no name");
+    path.parent()
+        .expect("watched directory has no parent")
+        .join(PROCESSING_DIRECTORY)
+        .join(name)
+}
+
+/// Takes ownership of a received file by moving it to the processing directory.
+///
+/// Both the watcher and the catch-up listing can send the same file, but as renaming is
+/// atomic only one of them will be able to claim it, the other one gets `None`.
+pub fn claim(file: ReceivedFile) -> impl Future<Item = Option<ReceivedFile>, Error = ()> {
+    let target = file
+        .parent()
+        .map(processing_directory)
+        .and_then(|directory| file.file_name().map(|name| directory.join(name)));
+
+    let target = match target {
+        Some(target) => target,
+        None => {
+            warn!("cannot claim {:?}: invalid path", file; "component" => "watcher");
+            return Either::B(future::ok(None));
+        }
+    };
+
+    Either::A(
+        rename(file.clone(), target.clone()).then(move |res| match res {
+            Ok(()) => {
+                debug!("claimed: {:?}", file; "component" => "watcher");
+                Ok(Some(target))
+            }
+            // Already claimed by another task
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                debug!("already claimed: {:?}", file; "component" => "watcher");
+                Ok(None)
+            }
+            Err(e) => {
+                warn!("cannot claim {:?}: {}", file, e; "component" => "watcher");
+                Ok(None)
+            }
+        }),
+    )
+}
+
+/// Gives back files claimed by a previous run that did not finish treating them
+fn recover(path: &WatchedDirectory) -> Result<(), Error> {
+    for entry in std_read_dir(processing_directory(path))? {
+        let file = entry?.path();
+        if let Some(name) = file.file_name() {
+            info!("recovering: {:?}", file; "component" => "watcher");
+            std_rename(&file, path.join(name))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn watch(path: &WatchedDirectory, cfg: CatchupConfig, tx: &QueueSender) {
+    info!("Starting file watcher on {:#?}", &path; "component" => "watcher");
+    // Try to create target dir
+    create_dir_all(path).expect("Could not create watched directory");
+    create_dir_all(processing_directory(path)).expect("Could not create processing directory");
+    recover(path).expect("Could not recover files from processing directory");
+    tokio::spawn(list_files(path.clone(), cfg, tx.clone()));
+    tokio::spawn(watch_files(path.clone(), tx.clone()));
+}
+
+/// Creates the archive directories and starts the cleaners enforcing retention on them
+pub fn retain(directory: &BaseDirectory, cfg: RetentionConfig) {
+    for (name, retention) in &[
+        (PROCESSED_DIRECTORY, cfg.success),
+        (FAILED_DIRECTORY, cfg.failure),
+    ] {
+        let path = directory.join(name);
+        create_dir_all(&path).expect("Could not create archive directory");
+        tokio::spawn(cleanup(path, *retention));
+    }
+}
+
+/// Returns true if the file was last modified more than `age` before `sys_time`
+pub fn older_than(path: &Path, sys_time: SystemTime, age: Duration) -> bool {
+    // TODO async filter (https://github.com/rust-lang-nursery/futures-rs/pull/728)
+    metadata(path)
+        .and_then(|metadata| metadata.modified())
+        // An error indicates a file in the future, let's approximate it to now
+        .map(|modified| {
+            sys_time
+                .duration_since(modified)
+                .unwrap_or_else(|_| Duration::new(0, 0))
+        })
+        .map(|duration| duration > age)
+        // If metadata can't be fetched, skip it for now
+        .unwrap_or_else(|e| {
+            warn!("list filter error: {}", e; "component" => "watcher");
+            false
+        })
+}
+
+fn list_files(
+    path: WatchedDirectory,
+    cfg: CatchupConfig,
+    tx: QueueSender,
+) -> impl Future<Item = (), Error = ()> {
+    Interval::new(Instant::now(), Duration::from_secs(cfg.frequency))
+        .map_err(|e| warn!("interval error: {}", e; "component" => "watcher"))
+        .for_each(move |_instant| {
+            debug!("listing {:?}", path; "component" => "watcher");
+
+            let tx = tx.clone();
+            let sys_time = SystemTime::now();
+
+            read_dir(path.clone())
+                .flatten_stream()
+                .take(cfg.limit)
+                .map_err(|e| warn!("list error: {}", e; "component" => "watcher"))
+                .filter(move |entry| older_than(&entry.path(), sys_time, CATCHUP_DELAY))
+                .for_each(move |entry| {
+                    let path = entry.path();
+                    debug!("list: {:?}", path; "component" => "watcher");
+                    tx.send(path)
+                })
+        })
+}
+
+/// Removes files older than the retention duration
+fn cleanup(path: PathBuf, retention: Duration) -> impl Future<Item = (), Error = ()> {
+    Interval::new(Instant::now(), CLEANUP_FREQUENCY)
+        .map_err(|e| warn!("interval error: {}", e; "component" => "cleanup"))
+        .for_each(move |_instant| {
+            debug!("cleaning {:?}", path; "component" => "cleanup");
+
+            let sys_time = SystemTime::now();
+
+            read_dir(path.clone())
+                .flatten_stream()
+                .map_err(|e| warn!("list error: {}", e; "component" => "cleanup"))
+                .filter(move |entry| older_than(&entry.path(), sys_time, retention))
+                .for_each(|entry| {
+                    let path = entry.path();
+                    remove_file(path.clone())
+                        .map(move |_| debug!("removed: {:?}", path; "component" => "cleanup"))
+                        .map_err(|e| warn!("remove error: {}", e; "component" => "cleanup"))
+                })
+        })
+}
+
+fn watch_stream(path: WatchedDirectory) -> inotify::EventStream<Vec<u8>> {
+    // https://github.com/linkerd/linkerd2-proxy/blob/c54377fe097208071a88d7b27501faa54ca212b0/lib/fs-watch/src/lib.rs#L189
+    let mut inotify = Inotify::init().expect("Could not initialize inotify");
+    inotify
+        // Only react to complete files, either closed after writing or moved into the directory
+        .add_watch(path.clone(), WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO)
+        .expect("Could not watch with inotify");
+    inotify.event_stream(Vec::from(&[0; 2048][..]))
+}
+
+fn watch_files(path: WatchedDirectory, tx: QueueSender) -> impl Future<Item = (), Error = ()> {
+    watch_stream(path.clone())
+        .map_err(|e| {
+            warn!("watch error: {}", e; "component" => "watcher");
+        })
+        .map(|entry| entry.name)
+        // If it is None, it means it is not an event on a file in the directory, skipping
+        .filter(|entry| entry.is_some())
+        .map(|entry| entry.expect("inotify entry has no name"))
+        // inotify only gives the name of the file, relative to the watched directory
+        .map(move |entry| path.join(entry))
+        .for_each(move |entry| tx.send(entry))
+}
+
+/// Extension of the file describing why the file next to it failed
+pub const ERROR_EXTENSION: &str = "error";
+
+/// Moves a treated file into the `processed` or `failed` directory,
+/// or removes it if the matching retention is zero.
+/// The cause of a failure is written in a file next to the archived file.
+pub fn finalize(
+    file: ReceivedFile,
+    directory: BaseDirectory,
+    retention: RetentionConfig,
+    result: &Result<(), Error>,
+) -> impl Future<Item = (), Error = ()> {
+    let success = result.is_ok();
+    let (target, duration) = if success {
+        (directory.join(PROCESSED_DIRECTORY), retention.success)
+    } else {
+        (directory.join(FAILED_DIRECTORY), retention.failure)
+    };
+
+    let action = if duration == Duration::new(0, 0) {
+        Either::A(remove_file(file.clone()))
+    } else {
+        let name = file
+            .file_name()
+            .expect("received file has no file name")
+            .to_owned();
+        let details = match *result {
+            Err(ref e) => {
+                let mut error_name = name.clone();
+                error_name.push(".");
+                error_name.push(ERROR_EXTENSION);
+                let details_file = file.clone();
+                // Details are best effort, the file is archived anyway
+                Either::A(
+                    write(target.join(error_name), format!("{}\n", e).into_bytes()).then(
+                        move |res| {
+                            if let Err(e) = res {
+                                warn!("could not write error details for {:?}: {}", details_file, e; "component" => "watcher");
+                            }
+                            Ok(())
+                        },
+                    ),
+                )
+            }
+            Ok(()) => Either::B(future::ok(())),
+        };
+        let archived = file.clone();
+        Either::B(details.and_then(move |_| rename(archived, target.join(name))))
+    };
+
+    action
+        .map(move |_| debug!("archived: {:?} (success: {})", file, success; "component" => "watcher"))
+        .map_err(|e| warn!("archive error: {}", e; "component" => "watcher"))
+}
+
+/// Moves a file that could not be treated because of an unavailable output to the spool
+pub fn spool(file: ReceivedFile, directory: BaseDirectory) -> impl Future<Item = (), Error = ()> {
+    let name = file
+        .file_name()
+        .expect("received file has no file name")
+        .to_owned();
+    rename(file.clone(), directory.join(SPOOL_DIRECTORY).join(name))
+        .map(move |_| info!("spooled: {:?}", file; "component" => "watcher"))
+        .map_err(|e| warn!("spool error: {}", e; "component" => "watcher"))
+}
+
+/// Returns the spooled files, oldest first.
+///
+/// Renaming keeps the modification time, so this is the order in which they were received.
+fn spooled_files(spool: &Path) -> impl Future<Item = Vec<PathBuf>, Error = Error> {
+    read_dir(spool.to_path_buf())
+        .flatten_stream()
+        .and_then(|entry| {
+            let file = entry.path();
+            future::poll_fn(move || entry.poll_metadata())
+                .and_then(|metadata| metadata.modified())
+                .map(move |modified| (modified, file))
+        })
+        .collect()
+        .map(|mut files| {
+            files.sort();
+            files.into_iter().map(|(_, file)| file).collect()
+        })
+        .map_err(Error::from)
+}
+
+/// Creates the spool directory and regularly queues spooled files, oldest first, once
+/// `probe` succeeds. Workers claim them from the spool like files from a watched
+/// directory, so they keep their order and are not picked by the watcher.
+pub fn drain<P, F>(directory: &BaseDirectory, cfg: CatchupConfig, probe: P, tx: &QueueSender)
+where
+    P: Fn() -> F + Send + Sync + 'static,
+    F: Future<Item = (), Error = Error> + Send + 'static,
+{
+    let spool = directory.join(SPOOL_DIRECTORY);
+    create_dir_all(&spool).expect("Could not create spool directory");
+    create_dir_all(processing_directory(&spool)).expect("Could not create processing directory");
+    recover(&spool).expect("Could not recover files from processing directory");
+    let probe = Arc::new(probe);
+    let tx = tx.clone();
+    tokio::spawn(
+        Interval::new(Instant::now(), Duration::from_secs(cfg.frequency))
+            .map_err(|e| warn!("interval error: {}", e; "component" => "watcher"))
+            .for_each(move |_instant| {
+                let probe = probe.clone();
+                let tx = tx.clone();
+                spooled_files(&spool)
+                    .map_err(|e| warn!("spool list error: {}", e; "component" => "watcher"))
+                    .and_then(move |files| {
+                        if files.is_empty() {
+                            return Either::A(future::ok(()));
+                        }
+                        Either::B(
+                            probe()
+                                .map_err(|e| debug!("output still unavailable: {}", e; "component" => "watcher"))
+                                .and_then(move |_| {
+                                    stream::iter_ok(files.into_iter().take(cfg.limit as usize))
+                                        .for_each(move |file| {
+                                            debug!("unspooled: {:?}", file; "component" => "watcher");
+                                            tx.send(file)
+                                        })
+                                }),
+                        )
+                    })
+                    // Try again at next tick
+                    .then(|_| Ok(()))
+            }),
+    );
+}
+
+pub fn read_file(path: &ReceivedFile) -> impl Future<Item = Vec<u8>, Error = Error> {
+    File::open(path.clone())
+        .and_then(|file| {
+            let buf: Vec<u8> = Vec::new();
+            tokio::io::read_to_end(file, buf)
+        })
+        .map(|item| item.1)
+        .map_err(Error::from)
+}
+
+/// Reads a file, refusing it without reading it when it is larger than `limit`.
+/// A compressed file is smaller than its content, so the limit on the decompressed
+/// size also applies to it.
+pub fn read_limited_file(
+    path: &ReceivedFile,
+    limit: u64,
+) -> impl Future<Item = Vec<u8>, Error = Error> {
+    let file = path.clone();
+    async_metadata(path.clone())
+        .map_err(Error::from)
+        .and_then(move |metadata| {
+            if metadata.len() > limit {
+                Either::A(future::err(Error::DecompressedFileTooLarge(limit)))
+            } else {
+                Either::B(read_file(&file))
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        fs::{create_dir_all, remove_file, File},
+        str::FromStr,
+    };
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn it_archives_files() {
+        let directory = PathBuf::from("tests/tmp/test_archive");
+        create_dir_all(directory.join("incoming")).unwrap();
+        create_dir_all(directory.join(FAILED_DIRECTORY)).unwrap();
+        let file = directory.join("incoming").join("runlog.log");
+        File::create(&file).unwrap();
+
+        let retention = RetentionConfig {
+            success: Duration::from_secs(0),
+            failure: Duration::from_secs(60),
+        };
+        Runtime::new()
+            .unwrap()
+            .block_on(finalize(
+                file.clone(),
+                directory.clone(),
+                retention,
+                &Err(Error::EmptyRunlog),
+            ))
+            .unwrap();
+        assert!(!file.exists());
+        assert!(directory.join(FAILED_DIRECTORY).join("runlog.log").exists());
+        assert_eq!(
+            std::fs::read_to_string(directory.join(FAILED_DIRECTORY).join("runlog.log.error"))
+                .unwrap(),
+            "agent run log is empty\n"
+        );
+
+        File::create(&file).unwrap();
+        Runtime::new()
+            .unwrap()
+            .block_on(finalize(
+                file.clone(),
+                directory.clone(),
+                retention,
+                &Ok(()),
+            ))
+            .unwrap();
+        assert!(!file.exists());
+        assert!(!directory
+            .join(PROCESSED_DIRECTORY)
+            .join("runlog.log")
+            .exists());
+
+        // cleanup
+        let _ = remove_file(directory.join(FAILED_DIRECTORY).join("runlog.log"));
+        let _ = remove_file(directory.join(FAILED_DIRECTORY).join("runlog.log.error"));
+    }
+
+    #[test]
+    fn it_drains_spooled_files_in_order() {
+        let directory = PathBuf::from("tests/tmp/test_drain");
+        let spool = directory.join(SPOOL_DIRECTORY);
+        let _ = std::fs::remove_dir_all(&directory);
+        create_dir_all(&spool).unwrap();
+        for name in &["first.log", "second.log"] {
+            File::create(spool.join(name)).unwrap();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let (tx, rx) = mpsc::channel(10);
+        let (stats, _stats_rx) = mpsc::channel(10);
+        let tx = QueueSender::new(tx, stats, Event::ReportQueued);
+        let cfg = CatchupConfig {
+            frequency: 10,
+            limit: 50,
+        };
+        let mut runtime = Runtime::new().unwrap();
+        let drained = runtime
+            .block_on(future::lazy(move || {
+                drain(&directory, cfg, || future::ok(()), &tx);
+                rx.take(2).collect()
+            }))
+            .unwrap();
+        assert_eq!(
+            drained,
+            vec![spool.join("first.log"), spool.join("second.log")]
+        );
+        // Claimed from the spool like received files
+        assert_eq!(
+            runtime.block_on(claim(drained[0].clone())).unwrap(),
+            Some(PathBuf::from(
+                "tests/tmp/test_drain/processing/spool/first.log"
+            ))
+        );
+
+        // cleanup
+        let _ = std::fs::remove_dir_all("tests/tmp/test_drain");
+    }
+
+    #[test]
+    fn it_claims_files_once() {
+        let watched = PathBuf::from("tests/tmp/test_claim/incoming");
+        create_dir_all(&watched).unwrap();
+        create_dir_all(processing_directory(&watched)).unwrap();
+        let file = watched.join("runlog.log");
+        File::create(&file).unwrap();
+
+        let mut runtime = Runtime::new().unwrap();
+        let claimed = runtime.block_on(claim(file.clone())).unwrap();
+        assert_eq!(
+            claimed,
+            Some(PathBuf::from(
+                "tests/tmp/test_claim/processing/incoming/runlog.log"
+            ))
+        );
+        assert_eq!(runtime.block_on(claim(file.clone())).unwrap(), None);
+
+        recover(&watched).unwrap();
+        assert!(file.exists());
+
+        // cleanup
+        let _ = remove_file(file);
+    }
+
+    #[test]
+    fn it_watches_files() {
+        // TODO improve tmp dir handling
+        create_dir_all("tests/tmp/test_watch").unwrap();
+        // just in case
+        let _ = remove_file("tests/tmp/test_watch/2019-01-24T15:55:01+00:00@root.log");
+
+        let watch = watch_stream(PathBuf::from_str("tests/tmp/test_watch").unwrap());
+        File::create("tests/tmp/test_watch/2019-01-24T15:55:01+00:00@root.log").unwrap();
+        let events = watch.take(1).wait().collect::<Vec<_>>();
+
+        assert_eq!(events.len(), 1);
+
+        for event in events {
+            if let Ok(event) = event {
+                assert_eq!(
+                    event.name.map(PathBuf::from).unwrap(),
+                    PathBuf::from_str("2019-01-24T15:55:01+00:00@root.log").unwrap()
+                );
+            }
+        }
+
+        // cleanup
+        let _ = remove_file("tests/tmp/test_watch/2019-01-24T15:55:01+00:00@root.log");
+    }
+}
@@ -39,17 +39,24 @@ pub mod error;
 pub mod fake;
 pub mod input;
 pub mod output;
+pub mod processing;
+pub mod signature;
 pub mod stats;
 
 use crate::{
     api::api,
     cli::parse,
     configuration::LogConfig,
-    configuration::{Configuration, ReportingOutputSelect, InventoryOutputSelect},
+    configuration::{Configuration, DatabaseConfig, InventoryOutputSelect, ReportingOutputSelect},
     data::nodes::parse_nodeslist,
     error::Error,
-    input::{serve_inventories, serve_reports},
-    output::database::{pg_pool, PgPool},
+    output::{
+        breaker::CircuitBreaker,
+        database::{self, check::check_schema, pg_pool, PgPool},
+        queue::{serve_upstream_queue, UpstreamQueue},
+        upstream::{self, Client},
+    },
+    processing::{inventory::serve_inventories, reporting::serve_reports},
     stats::Stats,
 };
 use data::nodes::NodesList;
@@ -58,11 +65,12 @@ use futures::{
     stream::Stream,
     sync::mpsc,
 };
-use slog::{o, slog_debug, slog_error, slog_info, slog_trace, Drain, Logger};
+use futures_cpupool::CpuPool;
+use slog::{o, slog_debug, slog_error, slog_info, slog_trace, slog_warn, Drain, Logger};
 use slog_async::Async;
 use slog_atomic::{AtomicSwitch, AtomicSwitchCtrl};
 use slog_kvfilter::KVFilter;
-use slog_scope::{debug, error, info, trace};
+use slog_scope::{debug, error, info, trace, warn};
 use slog_term::{CompactFormat, TermDecorator};
 use stats::{stats_job, Event};
 use std::collections::HashMap;
@@ -77,6 +85,14 @@ pub struct JobConfig {
     pub cfg: Configuration,
     pub nodes: NodesList,
     pub pool: Option<PgPool>,
+    pub database_executor: Option<CpuPool>,
+    /// Runs the decompression, checks and parsing of received files
+    pub processing_executor: CpuPool,
+    /// Opened while the database is unavailable
+    pub database_breaker: CircuitBreaker,
+    pub client: Option<Client>,
+    /// Files waiting to be uploaded by the client
+    pub upstream_queue: Option<UpstreamQueue>,
 }
 
 pub fn stats(rx: mpsc::Receiver<Event>) -> impl Future<Item = (), Error = ()> {
@@ -120,6 +136,44 @@ fn load_loggers(ctrl: &AtomicSwitchCtrl, cfg: &LogConfig) {
     ctrl.set(drain.map(slog::Fuse));
 }
 
+/// Refuses incompatible databases, but accepts unavailable ones as they can come back later
+fn check_database(cfg: &DatabaseConfig) -> Result<(), Error> {
+    match check_schema(cfg) {
+        Ok(ref issues) if issues.is_empty() => {
+            info!("Database schema is compatible");
+            Ok(())
+        }
+        Ok(issues) => {
+            for issue in &issues {
+                error!("Database schema: {}", issue);
+            }
+            Err(Error::IncompatibleDatabase(issues))
+        }
+        Err(ref e) if e.is_database_unavailable() => {
+            warn!(
+                "Could not check database schema, database is unavailable: {}",
+                e
+            );
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Prints a report of the database compatibility, for the `check-database` subcommand
+fn print_database_check(cfg: &DatabaseConfig) -> Result<(), Error> {
+    let issues = check_schema(cfg)?;
+    if issues.is_empty() {
+        println!("Database is compatible");
+        return Ok(());
+    }
+    println!("Database is not compatible:");
+    for issue in &issues {
+        println!("  - {}", issue);
+    }
+    Err(Error::IncompatibleDatabase(issues))
+}
+
 pub fn start() -> Result<(), Error> {
     // ---- Default logger for fist steps ----
 
@@ -143,6 +197,10 @@ pub fn start() -> Result<(), Error> {
 
     load_loggers(&ctrl, &cfg.logging);
 
+    if cli_cfg.check_database {
+        return print_database_check(&cfg.output.database);
+    }
+
     // ---- Start execution ----
 
     info!("Starting rudder relayd");
@@ -184,13 +242,39 @@ pub fn start() -> Result<(), Error> {
     let stats = Arc::new(RwLock::new(Stats::default()));
     let http_api = api(cfg.general.listen, shutdown, stats.clone());
 
-    let pool = if cfg.processing.reporting.output == ReportingOutputSelect::Database {
-        Some(pg_pool(&cfg.output.database)?)
+    let (pool, database_executor) =
+        if cfg.processing.reporting.output == ReportingOutputSelect::Database {
+            check_database(&cfg.output.database)?;
+            (
+                Some(pg_pool(&cfg.output.database)),
+                Some(database::executor(&cfg.output.database)),
+            )
+        } else {
+            (None, None)
+        };
+
+    let (client, upstream_queue) = if cfg.processing.reporting.output
+        == ReportingOutputSelect::Upstream
+        || cfg.processing.inventory.output == InventoryOutputSelect::Upstream
+    {
+        (
+            Some(upstream::client(&cfg.output.upstream)?),
+            Some(UpstreamQueue::new(&cfg.output.upstream.queue.directory)?),
+        )
     } else {
-        None
+        (None, None)
     };
 
-    let job_config = Arc::new(JobConfig { cfg, nodes, pool });
+    let job_config = Arc::new(JobConfig {
+        cfg,
+        nodes,
+        pool,
+        database_executor,
+        processing_executor: processing::executor(),
+        database_breaker: CircuitBreaker::new("database"),
+        client,
+        upstream_queue,
+    });
 
     // ---- Start server ----
 
@@ -203,6 +287,15 @@ pub fn start() -> Result<(), Error> {
         //tokio::spawn(shutdown);
         tokio::spawn(reload);
 
+        if let (Some(queue), Some(client)) = (&job_config.upstream_queue, &job_config.client) {
+            serve_upstream_queue(
+                queue,
+                client.clone(),
+                job_config.cfg.output.upstream.clone(),
+                tx_stats.clone(),
+            );
+        }
+
         if job_config.cfg.processing.reporting.output != ReportingOutputSelect::Disabled {
             serve_reports(job_config.clone(), tx_stats.clone());
         }
@@ -0,0 +1,146 @@
+// Copyright 2019 Normation SAS
+//
+// This file is part of Rudder.
+//
+// Rudder is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In accordance with the terms of section 7 (7. Additional Terms.) of
+// the GNU General Public License version 3, the copyright holders add
+// the following Additional permissions:
+// Notwithstanding to the terms of section 5 (5. Conveying Modified Source
+// Versions) and 6 (6. Conveying Non-Source Forms.) of the GNU General
+// Public License version 3, when you create a Related Module, this
+// Related Module is not considered as a part of the work and may be
+// distributed under the license agreement of your choice.
+// A "Related Module" means a set of sources files including their
+// documentation that, without modification of the Source Code, enables
+// supplementary functions or services in addition to those offered by
+// the Software.
+//
+// Rudder is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::error::Error;
+use futures::{
+    future::{self, Either, Future},
+    Stream,
+};
+use slog::{slog_debug, slog_info, slog_warn};
+use slog_scope::{debug, info, warn};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::timer::Interval;
+
+/// Shared availability state of an output.
+///
+/// It opens when the output is found unreachable, which pauses the intake of new files,
+/// and closes when a probe of the output succeeds again.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    /// Name of the output, for logs
+    name: &'static str,
+    open: Arc<AtomicBool>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            open: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open.load(Ordering::SeqCst)
+    }
+
+    pub fn open(&self) {
+        if !self.open.swap(true, Ordering::SeqCst) {
+            warn!("{} is unavailable, pausing intake", self.name; "component" => "breaker");
+        }
+    }
+
+    pub fn close(&self) {
+        if self.open.swap(false, Ordering::SeqCst) {
+            info!("{} is available again, resuming intake", self.name; "component" => "breaker");
+        }
+    }
+
+    /// Resolves immediately if the breaker is closed. Otherwise, runs `probe` every `interval`
+    /// and resolves once it succeeds, closing the breaker.
+    pub fn wait_closed<P, F>(
+        &self,
+        interval: Duration,
+        probe: P,
+    ) -> impl Future<Item = (), Error = ()>
+    where
+        P: Fn() -> F,
+        F: Future<Item = (), Error = Error>,
+    {
+        if !self.is_open() {
+            return Either::A(future::ok(()));
+        }
+
+        let breaker = self.clone();
+        let name = self.name;
+        Either::B(
+            Interval::new(Instant::now() + interval, interval)
+                .map_err(|e| warn!("interval error: {}", e; "component" => "breaker"))
+                .and_then(move |_instant| {
+                    probe().then(move |res| {
+                        if let Err(ref e) = res {
+                            debug!("{} is still unavailable: {}", name, e; "component" => "breaker");
+                        }
+                        Ok(res.is_ok())
+                    })
+                })
+                .skip_while(|available| Ok(!*available))
+                .into_future()
+                .map(move |_| breaker.close())
+                .map_err(|_| ()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn it_waits_for_a_successful_probe() {
+        let breaker = CircuitBreaker::new("output");
+        assert!(!breaker.is_open());
+        breaker.open();
+        assert!(breaker.is_open());
+
+        let probes = Arc::new(AtomicUsize::new(0));
+        let counter = probes.clone();
+        let wait = breaker.wait_closed(Duration::from_millis(1), move || {
+            if counter.fetch_add(1, Ordering::SeqCst) < 2 {
+                future::err(Error::Message("unavailable".to_string()))
+            } else {
+                future::ok(())
+            }
+        });
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(wait)
+            .unwrap();
+        assert_eq!(probes.load(Ordering::SeqCst), 3);
+        assert!(!breaker.is_open());
+    }
+}
@@ -0,0 +1,442 @@
+// Copyright 2019 Normation SAS
+//
+// This file is part of Rudder.
+//
+// Rudder is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In accordance with the terms of section 7 (7. Additional Terms.) of
+// the GNU General Public License version 3, the copyright holders add
+// the following Additional permissions:
+// Notwithstanding to the terms of section 5 (5. Conveying Modified Source
+// Versions) and 6 (6. Conveying Non-Source Forms.) of the GNU General
+// Public License version 3, when you create a Related Module, this
+// Related Module is not considered as a part of the work and may be
+// distributed under the license agreement of your choice.
+// A "Related Module" means a set of sources files including their
+// documentation that, without modification of the Source Code, enables
+// supplementary functions or services in addition to those offered by
+// the Software.
+//
+// Rudder is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Compatibility of the live database with what the relay reads and writes
+
+use crate::{configuration::DatabaseConfig, error::Error};
+use diesel::{
+    pg::PgConnection,
+    prelude::*,
+    sql_query,
+    sql_types::{Bool, Nullable, Text},
+};
+use std::fmt::{self, Display, Formatter};
+
+const TIMESTAMP: &str = "timestamp with time zone";
+
+/// Columns used by the relay, as `(table, column, type)`.
+///
+/// Needs to be kept in sync with the `table!` definitions, types are named as in
+/// `information_schema.columns`.
+const EXPECTED_COLUMNS: &[(&str, &str, &str)] = &[
+    ("ruddersysevents", "id", "bigint"),
+    ("ruddersysevents", "executiondate", TIMESTAMP),
+    ("ruddersysevents", "nodeid", "text"),
+    ("ruddersysevents", "directiveid", "text"),
+    ("ruddersysevents", "ruleid", "text"),
+    ("ruddersysevents", "serial", "integer"),
+    ("ruddersysevents", "component", "text"),
+    ("ruddersysevents", "keyvalue", "text"),
+    ("ruddersysevents", "executiontimestamp", TIMESTAMP),
+    ("ruddersysevents", "eventtype", "text"),
+    ("ruddersysevents", "policy", "text"),
+    ("ruddersysevents", "msg", "text"),
+    ("reportsexecution", "nodeid", "text"),
+    ("reportsexecution", "date", TIMESTAMP),
+    ("reportsexecution", "complete", "boolean"),
+    ("reportsexecution", "nodeconfigid", "text"),
+    ("reportsexecution", "insertionid", "bigint"),
+];
+
+/// Privileges needed on each table: reports are inserted with `RETURNING id`,
+/// and runs are looked up before being inserted
+const EXPECTED_PRIVILEGES: &[(&str, &str)] = &[
+    ("ruddersysevents", "INSERT"),
+    ("ruddersysevents", "SELECT"),
+    ("reportsexecution", "INSERT"),
+    ("reportsexecution", "SELECT"),
+];
+
+/// Column generated by the database from a sequence
+const SEQUENCE_COLUMN: (&str, &str) = ("ruddersysevents", "id");
+
+/// Column description from `information_schema.columns`
+#[derive(QueryableByName, Debug, Clone, PartialEq, Eq)]
+pub struct ColumnInfo {
+    #[sql_type = "Text"]
+    pub table_name: String,
+    #[sql_type = "Text"]
+    pub column_name: String,
+    #[sql_type = "Text"]
+    pub data_type: String,
+    #[sql_type = "Bool"]
+    pub nullable: bool,
+    #[sql_type = "Nullable<Text>"]
+    pub column_default: Option<String>,
+}
+
+#[derive(QueryableByName)]
+struct Granted {
+    #[sql_type = "Bool"]
+    granted: bool,
+}
+
+#[derive(QueryableByName)]
+struct CurrentUser {
+    #[sql_type = "Text"]
+    name: String,
+}
+
+/// Difference between the database and what the relay expects, with a way to fix it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaIssue {
+    MissingTable(String),
+    MissingColumn {
+        table: String,
+        column: String,
+    },
+    WrongType {
+        table: String,
+        column: String,
+        expected: String,
+        found: String,
+    },
+    /// A column without default value the relay does not fill, insertions would fail
+    UnfilledColumn {
+        table: String,
+        column: String,
+    },
+    /// The column is not generated from a sequence
+    MissingSequence {
+        table: String,
+        column: String,
+    },
+    MissingTablePrivilege {
+        table: String,
+        privilege: String,
+        user: String,
+    },
+    MissingSequencePrivilege {
+        sequence: String,
+        user: String,
+    },
+}
+
+impl Display for SchemaIssue {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            SchemaIssue::MissingTable(table) => write!(
+                fmt,
+                "table {} is missing, create it with the Rudder reports schema",
+                table
+            ),
+            SchemaIssue::MissingColumn { table, column } => write!(
+                fmt,
+                "column {}.{} is missing, update the schema to the current Rudder version",
+                table, column
+            ),
+            SchemaIssue::WrongType {
+                table,
+                column,
+                expected,
+                found,
+            } => write!(
+                fmt,
+                "column {}.{} has type {} instead of {}, update the schema to the current Rudder version",
+                table, column, found, expected
+            ),
+            SchemaIssue::UnfilledColumn { table, column } => write!(
+                fmt,
+                "column {}.{} is not filled by the relay but has no default value, \
+                 make it nullable or give it a default value",
+                table, column
+            ),
+            SchemaIssue::MissingSequence { table, column } => write!(
+                fmt,
+                "column {}.{} is not generated from a sequence, give it a nextval() default value",
+                table, column
+            ),
+            SchemaIssue::MissingTablePrivilege {
+                table,
+                privilege,
+                user,
+            } => write!(
+                fmt,
+                "missing {} privilege on table {}, run: grant {} on table {} to {};",
+                privilege,
+                table,
+                privilege.to_lowercase(),
+                table,
+                user
+            ),
+            SchemaIssue::MissingSequencePrivilege { sequence, user } => write!(
+                fmt,
+                "missing USAGE privilege on sequence {}, run: grant usage on sequence {} to {};",
+                sequence, sequence, user
+            ),
+        }
+    }
+}
+
+/// Returns the sequence used in a `nextval('<sequence>'::regclass)` default value
+fn sequence_name(column_default: &str) -> Option<&str> {
+    let start = column_default.find("nextval('")? + "nextval('".len();
+    let end = start + column_default[start..].find('\'')?;
+    Some(&column_default[start..end])
+}
+
+/// Compares the columns of the database with the expected ones
+pub fn column_issues(columns: &[ColumnInfo]) -> Vec<SchemaIssue> {
+    let mut issues = vec![];
+    let mut tables: Vec<&str> = EXPECTED_COLUMNS
+        .iter()
+        .map(|(table, _, _)| *table)
+        .collect();
+    tables.dedup();
+
+    for table in tables {
+        if !columns.iter().any(|column| column.table_name == table) {
+            issues.push(SchemaIssue::MissingTable(table.to_string()));
+            continue;
+        }
+
+        for (_, name, data_type) in EXPECTED_COLUMNS.iter().filter(|(t, _, _)| *t == table) {
+            match columns
+                .iter()
+                .find(|column| column.table_name == table && column.column_name == *name)
+            {
+                None => issues.push(SchemaIssue::MissingColumn {
+                    table: table.to_string(),
+                    column: name.to_string(),
+                }),
+                Some(column) if column.data_type != *data_type => {
+                    issues.push(SchemaIssue::WrongType {
+                        table: table.to_string(),
+                        column: name.to_string(),
+                        expected: data_type.to_string(),
+                        found: column.data_type.clone(),
+                    })
+                }
+                Some(_) => (),
+            }
+        }
+
+        for column in columns.iter().filter(|column| {
+            column.table_name == table
+                && !column.nullable
+                && column.column_default.is_none()
+                && !EXPECTED_COLUMNS
+                    .iter()
+                    .any(|(t, name, _)| *t == table && *name == column.column_name)
+        }) {
+            issues.push(SchemaIssue::UnfilledColumn {
+                table: table.to_string(),
+                column: column.column_name.clone(),
+            });
+        }
+    }
+
+    let (table, name) = SEQUENCE_COLUMN;
+    if let Some(column) = columns
+        .iter()
+        .find(|column| column.table_name == table && column.column_name == name)
+    {
+        if column
+            .column_default
+            .as_ref()
+            .and_then(|default| sequence_name(default))
+            .is_none()
+        {
+            issues.push(SchemaIssue::MissingSequence {
+                table: table.to_string(),
+                column: name.to_string(),
+            });
+        }
+    }
+
+    issues
+}
+
+fn columns(connection: &PgConnection) -> Result<Vec<ColumnInfo>, Error> {
+    Ok(sql_query(
+        "SELECT table_name::text AS table_name, column_name::text AS column_name, \
+         data_type::text AS data_type, is_nullable = 'YES' AS nullable, \
+         column_default::text AS column_default \
+         FROM information_schema.columns \
+         WHERE table_schema = current_schema() \
+         AND table_name IN ('ruddersysevents', 'reportsexecution')",
+    )
+    .load(connection)?)
+}
+
+fn privilege_issues(
+    connection: &PgConnection,
+    columns: &[ColumnInfo],
+) -> Result<Vec<SchemaIssue>, Error> {
+    let user = sql_query("SELECT current_user::text AS name")
+        .get_result::<CurrentUser>(connection)?
+        .name;
+    let mut issues = vec![];
+
+    // Privileges can only be checked on existing objects
+    for (table, privilege) in EXPECTED_PRIVILEGES
+        .iter()
+        .filter(|(table, _)| columns.iter().any(|column| column.table_name == *table))
+    {
+        let granted = sql_query("SELECT has_table_privilege($1, $2) AS granted")
+            .bind::<Text, _>(*table)
+            .bind::<Text, _>(*privilege)
+            .get_result::<Granted>(connection)?
+            .granted;
+        if !granted {
+            issues.push(SchemaIssue::MissingTablePrivilege {
+                table: table.to_string(),
+                privilege: privilege.to_string(),
+                user: user.clone(),
+            });
+        }
+    }
+
+    let (table, name) = SEQUENCE_COLUMN;
+    let sequence = columns
+        .iter()
+        .find(|column| column.table_name == table && column.column_name == name)
+        .and_then(|column| column.column_default.as_ref())
+        .and_then(|default| sequence_name(default));
+    if let Some(sequence) = sequence {
+        let granted = sql_query("SELECT has_sequence_privilege($1, 'USAGE') AS granted")
+            .bind::<Text, _>(sequence)
+            .get_result::<Granted>(connection)?
+            .granted;
+        if !granted {
+            issues.push(SchemaIssue::MissingSequencePrivilege {
+                sequence: sequence.to_string(),
+                user,
+            });
+        }
+    }
+
+    Ok(issues)
+}
+
+/// Connects to the database and lists what prevents the relay from using it.
+///
+/// It uses a dedicated connection, to fail fast when the database is down.
+pub fn check_schema(configuration: &DatabaseConfig) -> Result<Vec<SchemaIssue>, Error> {
+    let connection = PgConnection::establish(&configuration.url)?;
+    let columns = columns(&connection)?;
+    let mut issues = column_issues(&columns);
+    issues.extend(privilege_issues(&connection, &columns)?);
+    Ok(issues)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(table: &str, name: &str, data_type: &str) -> ColumnInfo {
+        ColumnInfo {
+            table_name: table.to_string(),
+            column_name: name.to_string(),
+            data_type: data_type.to_string(),
+            nullable: true,
+            column_default: None,
+        }
+    }
+
+    fn reference_columns() -> Vec<ColumnInfo> {
+        EXPECTED_COLUMNS
+            .iter()
+            .map(|(table, name, data_type)| {
+                let mut column = column(table, name, data_type);
+                if (*table, *name) == SEQUENCE_COLUMN {
+                    column.nullable = false;
+                    column.column_default = Some("nextval('serial'::regclass)".to_string());
+                }
+                column
+            })
+            .collect()
+    }
+
+    #[test]
+    fn it_parses_sequence_names() {
+        assert_eq!(sequence_name("nextval('serial'::regclass)"), Some("serial"));
+        assert_eq!(sequence_name("0"), None);
+    }
+
+    #[test]
+    fn it_accepts_reference_schema() {
+        assert_eq!(column_issues(&reference_columns()), vec![]);
+    }
+
+    #[test]
+    fn it_reports_schema_differences() {
+        let mut columns: Vec<ColumnInfo> = reference_columns()
+            .into_iter()
+            .filter(|column| column.table_name == "ruddersysevents" && column.column_name != "msg")
+            .collect();
+        for column in columns.iter_mut() {
+            if column.column_name == "serial" {
+                column.data_type = "text".to_string();
+            }
+            if column.column_name == "id" {
+                column.column_default = None;
+            }
+        }
+        let mut required = column("ruddersysevents", "detail", "text");
+        required.nullable = false;
+        columns.push(required);
+
+        assert_eq!(
+            column_issues(&columns),
+            vec![
+                SchemaIssue::WrongType {
+                    table: "ruddersysevents".to_string(),
+                    column: "serial".to_string(),
+                    expected: "integer".to_string(),
+                    found: "text".to_string(),
+                },
+                SchemaIssue::MissingColumn {
+                    table: "ruddersysevents".to_string(),
+                    column: "msg".to_string(),
+                },
+                SchemaIssue::UnfilledColumn {
+                    table: "ruddersysevents".to_string(),
+                    column: "detail".to_string(),
+                },
+                SchemaIssue::MissingTable("reportsexecution".to_string()),
+                SchemaIssue::MissingSequence {
+                    table: "ruddersysevents".to_string(),
+                    column: "id".to_string(),
+                },
+            ]
+        );
+        assert_eq!(
+            SchemaIssue::MissingTablePrivilege {
+                table: "reportsexecution".to_string(),
+                privilege: "INSERT".to_string(),
+                user: "rudderreports".to_string(),
+            }
+            .to_string(),
+            "missing INSERT privilege on table reportsexecution, \
+             run: grant insert on table reportsexecution to rudderreports;"
+        );
+    }
+}
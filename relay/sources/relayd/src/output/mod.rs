@@ -28,5 +28,8 @@
 // You should have received a copy of the GNU General Public License
 // along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
 
+pub mod breaker;
 pub mod database;
+pub mod queue;
+pub mod retry;
 pub mod upstream;
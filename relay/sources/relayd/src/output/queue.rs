@@ -0,0 +1,692 @@
+// Copyright 2019 Normation SAS
+//
+// This file is part of Rudder.
+//
+// Rudder is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In accordance with the terms of section 7 (7. Additional Terms.) of
+// the GNU General Public License version 3, the copyright holders add
+// the following Additional permissions:
+// Notwithstanding to the terms of section 5 (5. Conveying Modified Source
+// Versions) and 6 (6. Conveying Non-Source Forms.) of the GNU General
+// Public License version 3, when you create a Related Module, this
+// Related Module is not considered as a part of the work and may be
+// distributed under the license agreement of your choice.
+// A "Related Module" means a set of sources files including their
+// documentation that, without modification of the Source Code, enables
+// supplementary functions or services in addition to those offered by
+// the Software.
+//
+// Rudder is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Disk-backed queue of the files to upload to the upstream server.
+//!
+//! Queued files are named `<sequence>@<kind>@<original name>`, and are only removed once
+//! the upstream server accepted them, or moved to the `failed` directory of the queue once
+//! it refused them. The queue directory is synced after each
+//! addition, so that a crash at any time loses nothing. A crash between an upload and the
+//! removal of the file means it will be uploaded again.
+
+use crate::{
+    configuration::{QueueConfig, UpstreamConfig},
+    data::{inventory::InventoryType, reporting::RunInfo},
+    error::Error,
+    input::{compression::uncompressed_name, read_file, ERROR_EXTENSION, FAILED_DIRECTORY},
+    output::{
+        retry::retry,
+        upstream::{send_inventory, send_runlog, Client},
+    },
+    stats::{send_stat, Event},
+};
+use futures::{
+    future::{self, Either},
+    stream,
+    sync::mpsc,
+    Future, Stream,
+};
+use slog::{slog_debug, slog_warn};
+use slog_scope::{debug, warn};
+use std::{
+    collections::HashMap,
+    fs::{create_dir_all, read_dir as std_read_dir},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+    fs::{file::File, read_dir, remove_file, rename, write},
+    timer::Interval,
+};
+
+/// What a queued file is, which gives where it is uploaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuedKind {
+    Runlog,
+    /// Inventory or inventory signature
+    Inventory(InventoryType),
+}
+
+impl QueuedKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            QueuedKind::Runlog => "runlog",
+            QueuedKind::Inventory(InventoryType::New) => "inventory",
+            QueuedKind::Inventory(InventoryType::Update) => "inventory-update",
+        }
+    }
+
+    fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "runlog" => Some(QueuedKind::Runlog),
+            "inventory" => Some(QueuedKind::Inventory(InventoryType::New)),
+            "inventory-update" => Some(QueuedKind::Inventory(InventoryType::Update)),
+            _ => None,
+        }
+    }
+
+    fn sent(self) -> Event {
+        match self {
+            QueuedKind::Runlog => Event::ReportSent,
+            QueuedKind::Inventory(_) => Event::InventorySent,
+        }
+    }
+
+    fn refused(self) -> Event {
+        match self {
+            QueuedKind::Runlog => Event::ReportRefused,
+            QueuedKind::Inventory(_) => Event::InventoryRefused,
+        }
+    }
+
+    /// Only runlog retries are counted
+    fn retried(self) -> Option<Event> {
+        match self {
+            QueuedKind::Runlog => Some(Event::ReportRetried),
+            QueuedKind::Inventory(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct QueuedFile {
+    path: PathBuf,
+    sequence: u64,
+    kind: QueuedKind,
+    /// Name of the file before it was queued, used for the upload
+    name: String,
+    size: u64,
+    /// Renaming keeps the modification time, so this is when the file was received
+    modified: SystemTime,
+}
+
+impl QueuedFile {
+    /// Parses the queued file name, the metadata are filled by the caller
+    fn parse(path: PathBuf) -> Option<Self> {
+        let (sequence, kind, name) = {
+            let file_name = path.file_name()?.to_str()?;
+            let mut parts = file_name.splitn(3, '@');
+            (
+                parts.next()?.parse().ok()?,
+                QueuedKind::parse(parts.next()?)?,
+                parts.next()?.to_string(),
+            )
+        };
+        Some(QueuedFile {
+            path,
+            sequence,
+            kind,
+            name,
+            size: 0,
+            modified: SystemTime::UNIX_EPOCH,
+        })
+    }
+
+    /// Files with the same key are uploaded in order: the runlogs of a node,
+    /// or an inventory and its signature
+    fn key(&self) -> String {
+        match self.kind {
+            QueuedKind::Runlog => self
+                .name
+                .parse::<RunInfo>()
+                .map(|info| info.node_id)
+                .unwrap_or_else(|_| self.name.clone()),
+            QueuedKind::Inventory(_) => {
+                uncompressed_name(self.name.trim_end_matches(".sign")).to_string()
+            }
+        }
+    }
+
+    fn age(&self, now: SystemTime) -> Duration {
+        now.duration_since(self.modified)
+            .unwrap_or_else(|_| Duration::new(0, 0))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UpstreamQueue {
+    directory: PathBuf,
+    next_sequence: Arc<Mutex<u64>>,
+}
+
+impl UpstreamQueue {
+    /// Creates the queue directory, or takes over the files queued by a previous run
+    pub fn new(directory: &Path) -> Result<Self, Error> {
+        create_dir_all(directory.join(FAILED_DIRECTORY))?;
+        // Only called at startup, before the reactor runs
+        let mut next_sequence = 0;
+        for entry in std_read_dir(directory)? {
+            if let Some(file) = QueuedFile::parse(entry?.path()) {
+                next_sequence = next_sequence.max(file.sequence + 1);
+            }
+        }
+        Ok(Self {
+            directory: directory.to_path_buf(),
+            next_sequence: Arc::new(Mutex::new(next_sequence)),
+        })
+    }
+
+    /// Moves a file at the end of the queue.
+    ///
+    /// Renaming is atomic, so the file is either still in its directory or in the queue.
+    /// The queue directory is synced once the file is in, so that the rename survives a
+    /// crash before the file is removed from its directory by the caller.
+    pub fn push(&self, file: &Path, kind: QueuedKind) -> impl Future<Item = (), Error = Error> {
+        let sequence = {
+            let mut next_sequence = self
+                .next_sequence
+                .lock()
+                .expect("could not lock queue sequence");
+            *next_sequence += 1;
+            *next_sequence - 1
+        };
+        let name = file
+            .file_name()
+            .expect("queued file has no file name")
+            .to_string_lossy();
+        let target = self
+            .directory
+            .join(format!("{:020}@{}@{}", sequence, kind.as_str(), name));
+        let source = file.to_path_buf();
+        let directory = self.directory.clone();
+        rename(file.to_path_buf(), target)
+            .and_then(move |_| File::open(directory))
+            .and_then(|mut directory| future::poll_fn(move || directory.poll_sync_all()))
+            .map(move |_| debug!("queued: {:?}", source; "component" => "upstream"))
+            .map_err(Error::from)
+    }
+}
+
+/// Lists the queued files, in queue order
+fn queued_files(directory: &Path) -> impl Future<Item = Vec<QueuedFile>, Error = Error> {
+    read_dir(directory.to_path_buf())
+        .flatten_stream()
+        .filter(|entry| entry.file_name() != FAILED_DIRECTORY)
+        .filter_map(|entry| {
+            let file = QueuedFile::parse(entry.path());
+            if file.is_none() {
+                warn!("ignoring unknown file in queue: {:?}", entry.path(); "component" => "upstream");
+            }
+            file.map(|file| (entry, file))
+        })
+        .and_then(|(entry, mut file)| {
+            future::poll_fn(move || entry.poll_metadata()).and_then(move |metadata| {
+                file.size = metadata.len();
+                file.modified = metadata.modified()?;
+                Ok(file)
+            })
+        })
+        .collect()
+        .map(|mut files| {
+            files.sort_by_key(|file| file.sequence);
+            files
+        })
+        .map_err(Error::from)
+}
+
+/// Returns the number of files to drop, from the start of the queue,
+/// to get under the size and age limits
+fn over_limits(files: &[QueuedFile], now: SystemTime, cfg: &QueueConfig) -> usize {
+    let mut size: u64 = files.iter().map(|file| file.size).sum();
+    files
+        .iter()
+        .take_while(|file| {
+            let over = size > cfg.max_size || file.age(now) > cfg.max_age;
+            size -= file.size;
+            over
+        })
+        .count()
+}
+
+/// Groups the files by key, keeping the queue order inside each group
+fn groups(files: Vec<QueuedFile>) -> Vec<Vec<QueuedFile>> {
+    let mut groups: Vec<Vec<QueuedFile>> = vec![];
+    let mut indexes: HashMap<String, usize> = HashMap::new();
+    for file in files {
+        let key = file.key();
+        match indexes.get(&key) {
+            Some(&index) => groups[index].push(file),
+            None => {
+                indexes.insert(key, groups.len());
+                groups.push(vec![file]);
+            }
+        }
+    }
+    groups
+}
+
+fn upload(
+    file: &QueuedFile,
+    client: Client,
+    cfg: UpstreamConfig,
+    stats: mpsc::Sender<Event>,
+) -> impl Future<Item = (), Error = Error> {
+    let name = file.name.clone();
+    let kind = file.kind;
+    let attempts = cfg.retry.attempts;
+    read_file(&file.path).and_then(move |content| {
+        retry(
+            cfg.retry,
+            Error::is_upstream_unavailable,
+            move || match kind {
+                QueuedKind::Runlog => Either::A(send_runlog(&client, &cfg, &name, content.clone())),
+                QueuedKind::Inventory(inventory_type) => Either::B(send_inventory(
+                    &client,
+                    &cfg,
+                    inventory_type,
+                    &name,
+                    content.clone(),
+                )),
+            },
+            move |attempt, e, delay| {
+                warn!(
+                    "upload attempt {}/{} failed: {}, retrying in {:?}",
+                    attempt, attempts, e, delay;
+                    "component" => "upstream"
+                );
+                if kind == QueuedKind::Runlog {
+                    tokio::spawn(send_stat(stats.clone(), Event::ReportRetried));
+                }
+            },
+        )
+    })
+}
+
+fn remove(file: &QueuedFile) -> impl Future<Item = (), Error = ()> {
+    let path = file.path.clone();
+    remove_file(file.path.clone()).then(move |res| {
+        if let Err(e) = res {
+            warn!("could not remove queued file {:?}: {}", path, e; "component" => "upstream");
+        }
+        Ok(())
+    })
+}
+
+/// Moves a file refused by the upstream server to the failed directory of the queue.
+/// The cause of the failure is written in a file next to it.
+fn fail(file: &QueuedFile, error: &Error) -> impl Future<Item = (), Error = ()> {
+    let failed = file
+        .path
+        .parent()
+        .expect("queued file has no parent")
+        .join(FAILED_DIRECTORY);
+    let name = file
+        .path
+        .file_name()
+        .expect("queued file has no file name")
+        .to_owned();
+    let mut error_name = name.clone();
+    error_name.push(".");
+    error_name.push(ERROR_EXTENSION);
+    let path = file.path.clone();
+    write(failed.join(error_name), format!("{}\n", error).into_bytes())
+        .and_then(move |_| rename(path, failed.join(name)))
+        .map_err(|e| warn!("could not move refused file: {}", e; "component" => "upstream"))
+}
+
+/// Uploads the files of a group in order, removing each one once accepted.
+///
+/// Files refused by the upstream server are moved to the failed directory. Any other
+/// failure stops the group, to keep the file and the next ones for the next pass.
+fn upload_group(
+    group: Vec<QueuedFile>,
+    client: Client,
+    cfg: UpstreamConfig,
+    stats: mpsc::Sender<Event>,
+) -> impl Future<Item = (), Error = ()> {
+    stream::iter_ok(group)
+        .for_each(move |file| {
+            let stats = stats.clone();
+            upload(&file, client.clone(), cfg.clone(), stats.clone()).then(move |res| match res {
+                Ok(()) => {
+                    debug!("uploaded: {:?}", file.path; "component" => "upstream");
+                    tokio::spawn(send_stat(stats, file.kind.sent()));
+                    Either::A(Either::A(remove(&file)))
+                }
+                Err(ref e) if e.is_upload_refused() => {
+                    warn!("upload of {:?} refused, moving it to the failed files: {}", file.path, e; "component" => "upstream");
+                    tokio::spawn(send_stat(stats, file.kind.refused()));
+                    Either::A(Either::B(fail(&file, e)))
+                }
+                Err(e) => {
+                    warn!("upload of {:?} postponed: {}", file.path, e; "component" => "upstream");
+                    if let Some(event) = file.kind.retried() {
+                        tokio::spawn(send_stat(stats, event));
+                    }
+                    Either::B(future::err(()))
+                }
+            })
+        })
+        .then(|_| Ok(()))
+}
+
+/// Drops the files over the queue limits, then uploads the others
+fn upload_queue(
+    directory: &Path,
+    client: &Client,
+    cfg: &UpstreamConfig,
+    stats: &mpsc::Sender<Event>,
+) -> impl Future<Item = (), Error = ()> {
+    let client = client.clone();
+    let cfg = cfg.clone();
+    let stats = stats.clone();
+    queued_files(directory)
+        .map_err(|e| warn!("queue list error: {}", e; "component" => "upstream"))
+        .and_then(move |mut files| {
+            let now = SystemTime::now();
+            let dropped = over_limits(&files, now, &cfg.queue);
+            let removed = files
+                .drain(..dropped)
+                .map(|file| {
+                    warn!("dropping {:?}: upstream queue limits exceeded", file.path; "component" => "upstream");
+                    tokio::spawn(send_stat(stats.clone(), Event::UpstreamDropped));
+                    remove(&file)
+                })
+                .collect::<Vec<_>>();
+            tokio::spawn(send_stat(
+                stats.clone(),
+                Event::UpstreamQueue {
+                    length: files.len() as u64,
+                    age: files
+                        .first()
+                        .map(|file| file.age(now).as_secs())
+                        .unwrap_or(0),
+                },
+            ));
+
+            let workers = cfg.queue.workers;
+            future::join_all(removed).and_then(move |_| {
+                stream::iter_ok(groups(files))
+                    .map(move |group| {
+                        upload_group(group, client.clone(), cfg.clone(), stats.clone())
+                    })
+                    .buffer_unordered(workers)
+                    .for_each(|_| Ok(()))
+            })
+        })
+        // Try again at next pass
+        .then(|_| Ok(()))
+}
+
+/// Regularly uploads the queued files, one pass at a time
+pub fn serve_upstream_queue(
+    queue: &UpstreamQueue,
+    client: Client,
+    cfg: UpstreamConfig,
+    stats: mpsc::Sender<Event>,
+) {
+    let directory = queue.directory.clone();
+    tokio::spawn(
+        Interval::new(Instant::now(), cfg.queue.frequency)
+            .map_err(|e| warn!("interval error: {}", e; "component" => "upstream"))
+            .for_each(move |_instant| upload_queue(&directory, &client, &cfg, &stats)),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        configuration::{RetryConfig, TlsConfig},
+        output::upstream::client,
+    };
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    /// Answers `status` to each of the `connections` HTTP requests, and returns the url
+    /// of the server
+    fn server(status: &'static str, connections: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port());
+        thread::spawn(move || {
+            for stream in listener.incoming().take(connections) {
+                let mut stream = stream.unwrap();
+                let mut buffer = [0; 1024];
+                let _ = stream.read(&mut buffer);
+                stream
+                    .write_all(
+                        format!(
+                            "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                            status
+                        )
+                        .as_bytes(),
+                    )
+                    .unwrap();
+            }
+        });
+        url
+    }
+
+    /// Uploads the queue once to a server answering `status`, and returns the files left
+    /// and the failed files
+    fn upload_once(name: &str, status: &'static str) -> (Vec<String>, Vec<String>, Vec<Event>) {
+        let directory = Path::new("tests/tmp/test_upload").join(name);
+        let _ = std::fs::remove_dir_all(&directory);
+        let incoming = directory.join("incoming");
+        create_dir_all(&incoming).unwrap();
+        let queue_directory = directory.join("queue");
+        let queue = UpstreamQueue::new(&queue_directory).unwrap();
+        let cfg = UpstreamConfig {
+            url: server(status, 1),
+            retry: RetryConfig {
+                attempts: 1,
+                initial_delay: Duration::from_secs(1),
+                max_delay: Duration::from_secs(1),
+            },
+            tls: TlsConfig {
+                allow_http: true,
+                ..TlsConfig::default()
+            },
+            queue: QueueConfig {
+                directory: queue_directory.clone(),
+                frequency: Duration::from_secs(10),
+                workers: 1,
+                max_size: 1_024,
+                max_age: Duration::from_secs(60),
+            },
+        };
+        let client = client(&cfg).unwrap();
+        let (stats, rx) = mpsc::channel(10);
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let runlog = incoming.join("2018-08-24T15:55:01+00:00@root.log");
+        std::fs::write(&runlog, "runlog").unwrap();
+        runtime
+            .block_on(queue.push(&runlog, QueuedKind::Runlog))
+            .unwrap();
+        runtime
+            .block_on(upload_queue(&queue_directory, &client, &cfg, &stats))
+            .unwrap();
+        drop(stats);
+        // Let the spawned statistics reach the channel
+        runtime.shutdown_on_idle().wait().unwrap();
+
+        let list = |directory: &Path| {
+            let mut files: Vec<String> = std::fs::read_dir(directory)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+                .filter(|name| name != FAILED_DIRECTORY)
+                .collect();
+            files.sort();
+            files
+        };
+        let files = list(&queue_directory);
+        let failed = list(&queue_directory.join(FAILED_DIRECTORY));
+        let events = rx
+            .wait()
+            .map(|event| event.unwrap())
+            .filter(|event| match event {
+                Event::UpstreamQueue { .. } => false,
+                _ => true,
+            })
+            .collect();
+        let _ = std::fs::remove_dir_all(&directory);
+        (files, failed, events)
+    }
+
+    #[test]
+    fn it_keeps_files_until_refused() {
+        let queued = "00000000000000000000@runlog@2018-08-24T15:55:01+00:00@root.log";
+
+        let (files, failed, events) = upload_once("rate_limited", "429 Too Many Requests");
+        assert_eq!(files, vec![queued]);
+        assert!(failed.is_empty());
+        assert_eq!(events, vec![Event::ReportRetried]);
+
+        for (name, status) in &[
+            ("bad_request", "400 Bad Request"),
+            ("unauthorized", "401 Unauthorized"),
+            ("not_found", "404 Not Found"),
+        ] {
+            let (files, failed, events) = upload_once(name, status);
+            assert!(files.is_empty());
+            assert_eq!(
+                failed,
+                vec![queued.to_string(), format!("{}.error", queued)]
+            );
+            assert_eq!(events, vec![Event::ReportRefused]);
+        }
+    }
+
+    fn queued(sequence: u64, name: &str, size: u64, age: u64, now: SystemTime) -> QueuedFile {
+        let mut file = QueuedFile::parse(PathBuf::from(format!(
+            "tests/tmp/upstream-queue/{:020}@runlog@{}",
+            sequence, name
+        )))
+        .unwrap();
+        file.size = size;
+        file.modified = now - Duration::from_secs(age);
+        file
+    }
+
+    #[test]
+    fn it_parses_queued_files() {
+        let file = QueuedFile::parse(PathBuf::from(
+            "00000000000000000012@runlog@2018-08-24T15:55:01+00:00@root.log",
+        ))
+        .unwrap();
+        assert_eq!(file.sequence, 12);
+        assert_eq!(file.kind, QueuedKind::Runlog);
+        assert_eq!(file.name, "2018-08-24T15:55:01+00:00@root.log");
+        assert_eq!(file.key(), "root");
+
+        let signature = QueuedFile::parse(PathBuf::from(
+            "00000000000000000013@inventory-update@node-1234.ocs.sign",
+        ))
+        .unwrap();
+        assert_eq!(signature.kind, QueuedKind::Inventory(InventoryType::Update));
+        assert_eq!(signature.key(), "node-1234.ocs");
+        let inventory = QueuedFile::parse(PathBuf::from(
+            "00000000000000000012@inventory-update@node-1234.ocs.gz",
+        ))
+        .unwrap();
+        assert_eq!(inventory.key(), signature.key());
+
+        assert!(QueuedFile::parse(PathBuf::from("node-1234.ocs")).is_none());
+        assert!(QueuedFile::parse(PathBuf::from("12@unknown@node-1234.ocs")).is_none());
+    }
+
+    #[test]
+    fn it_drops_oldest_files_over_limits() {
+        let now = SystemTime::now();
+        let cfg = QueueConfig {
+            directory: PathBuf::from("tests/tmp/upstream-queue/"),
+            frequency: Duration::from_secs(10),
+            workers: 1,
+            max_size: 100,
+            max_age: Duration::from_secs(3600),
+        };
+        let files = vec![
+            queued(1, "2018-08-24T15:55:01+00:00@a.log", 10, 7200, now),
+            queued(2, "2018-08-24T15:55:01+00:00@b.log", 50, 60, now),
+            queued(3, "2018-08-24T15:56:01+00:00@a.log", 50, 60, now),
+            queued(4, "2018-08-24T15:57:01+00:00@a.log", 40, 0, now),
+        ];
+        // Too old, then too large
+        assert_eq!(over_limits(&files, now, &cfg), 2);
+        assert_eq!(over_limits(&files[2..], now, &cfg), 0);
+
+        let groups = groups(files);
+        assert_eq!(
+            groups
+                .iter()
+                .map(|group| group.iter().map(|file| file.sequence).collect())
+                .collect::<Vec<Vec<u64>>>(),
+            vec![vec![1, 3, 4], vec![2]]
+        );
+    }
+
+    #[test]
+    fn it_queues_files_in_order() {
+        let directory = Path::new("tests/tmp/test_queue");
+        let _ = std::fs::remove_dir_all(directory);
+        let incoming = directory.join("incoming");
+        create_dir_all(&incoming).unwrap();
+        let queue_directory = directory.join("queue");
+        let queue = UpstreamQueue::new(&queue_directory).unwrap();
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        for name in &["2018-08-24T15:55:01+00:00@root.log", "node-1234.ocs"] {
+            std::fs::write(incoming.join(name), name).unwrap();
+        }
+        runtime
+            .block_on(queue.push(
+                &incoming.join("2018-08-24T15:55:01+00:00@root.log"),
+                QueuedKind::Runlog,
+            ))
+            .unwrap();
+        runtime
+            .block_on(queue.push(
+                &incoming.join("node-1234.ocs"),
+                QueuedKind::Inventory(InventoryType::New),
+            ))
+            .unwrap();
+
+        // A new queue takes over the existing files
+        let queue = UpstreamQueue::new(&queue_directory).unwrap();
+        assert_eq!(*queue.next_sequence.lock().unwrap(), 2);
+        let files = runtime.block_on(queued_files(&queue_directory)).unwrap();
+        assert_eq!(
+            files
+                .iter()
+                .map(|file| (file.sequence, file.name.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                (0, "2018-08-24T15:55:01+00:00@root.log"),
+                (1, "node-1234.ocs")
+            ]
+        );
+        assert!(!incoming.join("node-1234.ocs").exists());
+    }
+}
@@ -0,0 +1,126 @@
+// Copyright 2019 Normation SAS
+//
+// This file is part of Rudder.
+//
+// Rudder is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In accordance with the terms of section 7 (7. Additional Terms.) of
+// the GNU General Public License version 3, the copyright holders add
+// the following Additional permissions:
+// Notwithstanding to the terms of section 5 (5. Conveying Modified Source
+// Versions) and 6 (6. Conveying Non-Source Forms.) of the GNU General
+// Public License version 3, when you create a Related Module, this
+// Related Module is not considered as a part of the work and may be
+// distributed under the license agreement of your choice.
+// A "Related Module" means a set of sources files including their
+// documentation that, without modification of the Source Code, enables
+// supplementary functions or services in addition to those offered by
+// the Software.
+//
+// Rudder is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::{configuration::RetryConfig, error::Error};
+use futures::future::{self, Either, Future, Loop};
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+
+/// Runs `operation` until it succeeds, fails with an error `retryable` refuses, or has been
+/// tried `cfg.attempts` times. The delay between two attempts doubles each time, up to
+/// `cfg.max_delay`. `on_retry` is called with the attempt number, its error and the delay
+/// before the next attempt.
+pub fn retry<T, O, F, N>(
+    cfg: RetryConfig,
+    retryable: fn(&Error) -> bool,
+    mut operation: O,
+    on_retry: N,
+) -> impl Future<Item = T, Error = Error>
+where
+    O: FnMut() -> F,
+    F: Future<Item = T, Error = Error>,
+    N: Fn(u32, &Error, Duration) + Clone,
+{
+    future::loop_fn((1, cfg.initial_delay), move |(attempt, delay)| {
+        let on_retry = on_retry.clone();
+        operation().then(move |res| match res {
+            Ok(item) => Either::B(future::ok(Loop::Break(item))),
+            Err(e) => {
+                if attempt >= cfg.attempts || !retryable(&e) {
+                    return Either::B(future::err(e));
+                }
+                on_retry(attempt, &e, delay);
+                Either::A(
+                    Delay::new(Instant::now() + delay)
+                        .map_err(Error::from)
+                        .map(move |_| {
+                            Loop::Continue((attempt + 1, (delay * 2).min(cfg.max_delay)))
+                        }),
+                )
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    fn is_message(error: &Error) -> bool {
+        match error {
+            Error::Message(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Number of attempts made by `retry` for an operation failing `failures` times with `error`
+    fn attempts(failures: usize, error: fn() -> Error) -> (usize, Result<(), Error>) {
+        let cfg = RetryConfig {
+            attempts: 3,
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(2),
+        };
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let res = tokio::runtime::Runtime::new().unwrap().block_on(retry(
+            cfg,
+            is_message,
+            move || {
+                if counter.fetch_add(1, Ordering::SeqCst) < failures {
+                    future::err(error())
+                } else {
+                    future::ok(())
+                }
+            },
+            |_, _, _| (),
+        ));
+        (calls.load(Ordering::SeqCst), res)
+    }
+
+    #[test]
+    fn it_retries_with_backoff() {
+        let message = || Error::Message("unavailable".to_string());
+        let (calls, res) = attempts(2, message);
+        assert_eq!(calls, 3);
+        assert!(res.is_ok());
+
+        let (calls, res) = attempts(5, message);
+        assert_eq!(calls, 3);
+        assert!(res.is_err());
+
+        let (calls, res) = attempts(5, || Error::EmptyRunlog);
+        assert_eq!(calls, 1);
+        assert!(res.is_err());
+    }
+}
@@ -27,3 +27,382 @@
 //
 // You should have received a copy of the GNU General Public License
 // along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::{
+    configuration::{TlsConfig, UpstreamConfig},
+    data::inventory::InventoryType,
+    error::Error,
+    signature::key_hash,
+};
+use futures::{
+    future::{self, Either},
+    Future,
+};
+use hyper::{client::HttpConnector, Body, Request, Uri};
+use hyper_openssl::HttpsConnector;
+use openssl::{
+    ssl::{SslConnector, SslConnectorBuilder, SslFiletype, SslMethod, SslVerifyMode},
+    x509::X509StoreContextRef,
+};
+use std::time::Duration;
+use tokio::prelude::FutureExt;
+
+pub type Client = hyper::Client<HttpsConnector<HttpConnector>>;
+
+/// Threads used to resolve the upstream server name
+const DNS_THREADS: usize = 1;
+/// Uploads taking longer are considered failed
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// With a pinned key, the upstream server is trusted if and only if its certificate
+/// has this key, whatever its issuer. The callback is called for each certificate of
+/// the chain, the server certificate being at depth 0.
+fn verify_pinned_key(pin: &str, context: &X509StoreContextRef) -> bool {
+    if context.error_depth() != 0 {
+        return true;
+    }
+    context
+        .current_cert()
+        .and_then(|cert| cert.public_key().ok())
+        .and_then(|key| key_hash(&key).ok())
+        .map(|hash| hash == pin)
+        .unwrap_or(false)
+}
+
+fn tls_connector(cfg: &TlsConfig) -> Result<SslConnectorBuilder, Error> {
+    let mut ssl = SslConnector::builder(SslMethod::tls())?;
+
+    if let Some(ref ca_certificate) = cfg.ca_certificate {
+        ssl.set_ca_file(ca_certificate)?;
+    }
+
+    match (&cfg.client_certificate, &cfg.client_key) {
+        (Some(certificate), Some(key)) => {
+            ssl.set_certificate_chain_file(certificate)?;
+            ssl.set_private_key_file(key, SslFiletype::PEM)?;
+            ssl.check_private_key()?;
+        }
+        (None, None) => (),
+        _ => {
+            return Err(Error::InvalidUpstreamTls(
+                "client certificate and client key need to be set together".to_string(),
+            ))
+        }
+    }
+
+    match cfg.server_key_hash {
+        Some(ref pin) => {
+            // Hashes are compared as computed by `key_hash`, in lowercase
+            let pin = pin.to_lowercase();
+            let valid = pin.starts_with("sha256:") && {
+                let hash = &pin["sha256:".len()..];
+                hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())
+            };
+            if !valid {
+                return Err(Error::InvalidUpstreamTls(format!(
+                    "server key hash {} is not in the sha256:<64 hex digits> format",
+                    pin
+                )));
+            }
+            ssl.set_verify_callback(SslVerifyMode::PEER, move |_preverified, context| {
+                verify_pinned_key(&pin, context)
+            });
+        }
+        None if cfg.strict => {
+            return Err(Error::InvalidUpstreamTls(
+                "strict mode requires a server key hash".to_string(),
+            ))
+        }
+        None => (),
+    }
+
+    Ok(ssl)
+}
+
+/// Files are sent in cleartext to an `http://` url, which is only allowed explicitly
+/// and never along with key pinning or strict mode, as they would be silently ignored.
+fn check_scheme(cfg: &UpstreamConfig) -> Result<(), Error> {
+    let uri = cfg
+        .url
+        .parse::<Uri>()
+        .map_err(|e| Error::InvalidUpstreamTls(format!("invalid url {}: {}", cfg.url, e)))?;
+    match uri.scheme_str() {
+        Some("https") => Ok(()),
+        Some("http") if cfg.tls.allow_http => {
+            if cfg.tls.strict || cfg.tls.server_key_hash.is_some() {
+                Err(Error::InvalidUpstreamTls(format!(
+                    "url {} is not https, which is required by strict mode and key pinning",
+                    cfg.url
+                )))
+            } else {
+                Ok(())
+            }
+        }
+        _ => Err(Error::InvalidUpstreamTls(format!(
+            "url {} is not https, set allow_http to send files in cleartext",
+            cfg.url
+        ))),
+    }
+}
+
+pub fn client(cfg: &UpstreamConfig) -> Result<Client, Error> {
+    check_scheme(cfg)?;
+    let mut http = HttpConnector::new(DNS_THREADS);
+    // The TLS connector handles https urls, and http ones were checked by `check_scheme`
+    http.enforce_http(false);
+    let https = HttpsConnector::with_connector(http, tls_connector(&cfg.tls)?)?;
+    Ok(hyper::Client::builder().build(https))
+}
+
+/// Files are uploaded with a `PUT` in a directory of the upstream server
+fn upload(
+    client: &Client,
+    cfg: &UpstreamConfig,
+    directory: &str,
+    file_name: &str,
+    content: Vec<u8>,
+) -> impl Future<Item = (), Error = Error> {
+    let url = format!(
+        "{}/{}/{}",
+        cfg.url.trim_end_matches('/'),
+        directory,
+        file_name
+    );
+
+    let request = match Request::put(url.as_str()).body(Body::from(content)) {
+        Ok(request) => request,
+        Err(e) => {
+            return Either::B(future::err(Error::Message(format!(
+                "invalid upload url {}: {}",
+                url, e
+            ))))
+        }
+    };
+
+    Either::A(
+        client
+            .request(request)
+            .map_err(Error::from)
+            .and_then(|response| {
+                if response.status().is_success() {
+                    Ok(())
+                } else {
+                    Err(Error::HttpStatus(response.status()))
+                }
+            })
+            .timeout(UPLOAD_TIMEOUT)
+            .map_err(|e| {
+                if e.is_elapsed() {
+                    Error::UploadTimeout
+                } else if e.is_timer() {
+                    Error::Timer(e.into_timer().expect("timeout error is a timer error"))
+                } else {
+                    e.into_inner().expect("timeout error is an upload error")
+                }
+            }),
+    )
+}
+
+/// Inventories are uploaded to the directory matching their type on the upstream server
+pub fn send_inventory(
+    client: &Client,
+    cfg: &UpstreamConfig,
+    inventory_type: InventoryType,
+    file_name: &str,
+    content: Vec<u8>,
+) -> impl Future<Item = (), Error = Error> {
+    let directory = match inventory_type {
+        InventoryType::New => "inventories",
+        InventoryType::Update => "inventory-updates",
+    };
+    upload(client, cfg, directory, file_name, content)
+}
+
+/// Runlogs are uploaded as received from the agent, signature included
+pub fn send_runlog(
+    client: &Client,
+    cfg: &UpstreamConfig,
+    file_name: &str,
+    content: Vec<u8>,
+) -> impl Future<Item = (), Error = Error> {
+    upload(client, cfg, "reports", file_name, content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::{QueueConfig, RetryConfig};
+    use openssl::{
+        asn1::Asn1Time,
+        bn::BigNum,
+        hash::MessageDigest,
+        pkey::{PKey, Private},
+        rsa::Rsa,
+        ssl::SslAcceptor,
+        x509::{X509Name, X509},
+    };
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        path::PathBuf,
+        thread,
+    };
+
+    fn self_signed() -> (PKey<Private>, X509) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+        let mut certificate = X509::builder().unwrap();
+        certificate.set_version(2).unwrap();
+        certificate
+            .set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        certificate.set_subject_name(&name).unwrap();
+        certificate.set_issuer_name(&name).unwrap();
+        certificate.set_pubkey(&key).unwrap();
+        certificate
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        certificate
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        certificate.sign(&key, MessageDigest::sha256()).unwrap();
+        (key, certificate.build())
+    }
+
+    /// Serves `connections` TLS connections on a local port, answering `201 Created`
+    /// to each request, and returns the url of the server
+    fn server(key: PKey<Private>, certificate: X509, connections: usize) -> String {
+        let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        acceptor.set_certificate(&certificate).unwrap();
+        let acceptor = acceptor.build();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "https://127.0.0.1:{}",
+            listener.local_addr().unwrap().port()
+        );
+
+        thread::spawn(move || {
+            for stream in listener.incoming().take(connections) {
+                let mut stream = match acceptor.accept(stream.unwrap()) {
+                    Ok(stream) => stream,
+                    // Refused by the client
+                    Err(_) => continue,
+                };
+                let mut request = vec![];
+                let mut buffer = [0; 1024];
+                while !String::from_utf8_lossy(&request).contains("\r\n\r\nrunlog") {
+                    match stream.read(&mut buffer) {
+                        Ok(0) | Err(_) => break,
+                        Ok(read) => request.extend_from_slice(&buffer[..read]),
+                    }
+                }
+                stream
+                    .write_all(
+                        b"HTTP/1.1 201 Created\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    )
+                    .unwrap();
+            }
+        });
+        url
+    }
+
+    fn config(url: String, tls: TlsConfig) -> UpstreamConfig {
+        UpstreamConfig {
+            url,
+            retry: RetryConfig {
+                attempts: 1,
+                initial_delay: Duration::from_secs(1),
+                max_delay: Duration::from_secs(1),
+            },
+            tls,
+            queue: QueueConfig {
+                directory: PathBuf::from("tests/tmp/upstream-queue/"),
+                frequency: Duration::from_secs(10),
+                workers: 1,
+                max_size: 1_024,
+                max_age: Duration::from_secs(60),
+            },
+        }
+    }
+
+    fn send(cfg: &UpstreamConfig) -> Result<(), Error> {
+        let client = client(cfg)?;
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(send_runlog(
+                &client,
+                cfg,
+                "2018-08-24T15:55:01+00:00@root.log",
+                b"runlog".to_vec(),
+            ))
+    }
+
+    #[test]
+    fn it_pins_upstream_keys() {
+        let (key, certificate) = self_signed();
+        let public = PKey::public_key_from_der(&key.public_key_to_der().unwrap()).unwrap();
+        let hash = key_hash(&public).unwrap();
+        let url = server(key, certificate, 4);
+        let tls = TlsConfig {
+            ca_certificate: None,
+            client_certificate: None,
+            client_key: None,
+            server_key_hash: None,
+            strict: false,
+            allow_http: false,
+        };
+
+        // Self-signed certificate is not trusted by default
+        assert!(send(&config(url.clone(), tls.clone())).is_err());
+        // but is with its pinned key
+        let mut pinned = tls.clone();
+        pinned.server_key_hash = Some(hash.clone());
+        assert!(send(&config(url.clone(), pinned)).is_ok());
+        // and not with another one
+        let mut wrong = tls.clone();
+        wrong.server_key_hash = Some(format!("sha256:{}", "00".repeat(32)));
+        assert!(send(&config(url.clone(), wrong)).is_err());
+
+        let mut uppercase = tls.clone();
+        uppercase.server_key_hash = Some(hash.to_uppercase());
+        assert!(send(&config(url.clone(), uppercase)).is_ok());
+        let mut truncated = tls.clone();
+        truncated.server_key_hash = Some(format!("sha256:{}", "00".repeat(31)));
+        assert!(client(&config(url.clone(), truncated)).is_err());
+        let mut not_hex = tls.clone();
+        not_hex.server_key_hash = Some(format!("sha256:{}", "zz".repeat(32)));
+        assert!(client(&config(url.clone(), not_hex)).is_err());
+
+        let mut strict = tls.clone();
+        strict.strict = true;
+        assert!(client(&config(url.clone(), strict)).is_err());
+        // Rejected before any file is read
+        let mut certificate_only = tls;
+        certificate_only.client_certificate = Some("tests/files/relayd.conf".into());
+        assert!(client(&config(url, certificate_only)).is_err());
+    }
+
+    #[test]
+    fn it_refuses_cleartext_upstream_urls() {
+        let url = "http://127.0.0.1:8080".to_string();
+        let tls = TlsConfig::default();
+        assert!(client(&config(url.clone(), tls.clone())).is_err());
+
+        let mut allowed = tls.clone();
+        allowed.allow_http = true;
+        assert!(client(&config(url.clone(), allowed.clone())).is_ok());
+        // Pinning and strict mode cannot be enforced without TLS
+        let mut pinned = allowed.clone();
+        pinned.server_key_hash = Some(format!("sha256:{}", "00".repeat(32)));
+        assert!(client(&config(url.clone(), pinned)).is_err());
+        let mut strict = allowed;
+        strict.strict = true;
+        assert!(client(&config(url, strict)).is_err());
+
+        assert!(client(&config("ftp://127.0.0.1".to_string(), tls.clone())).is_err());
+        assert!(client(&config("not a url".to_string(), tls)).is_err());
+    }
+}
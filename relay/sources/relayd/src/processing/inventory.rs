@@ -0,0 +1,399 @@
+// Copyright 2019 Normation SAS
+//
+// This file is part of Rudder.
+//
+// Rudder is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In accordance with the terms of section 7 (7. Additional Terms.) of
+// the GNU General Public License version 3, the copyright holders add
+// the following Additional permissions:
+// Notwithstanding to the terms of section 5 (5. Conveying Modified Source
+// Versions) and 6 (6. Conveying Non-Source Forms.) of the GNU General
+// Public License version 3, when you create a Related Module, this
+// Related Module is not considered as a part of the work and may be
+// distributed under the license agreement of your choice.
+// A "Related Module" means a set of sources files including their
+// documentation that, without modification of the Source Code, enables
+// supplementary functions or services in addition to those offered by
+// the Software.
+//
+// Rudder is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::{
+    configuration::InventoryOutputSelect,
+    data::inventory::InventoryType,
+    error::Error,
+    input::{
+        claim,
+        compression::{compressed_names, decompress, uncompressed_name},
+        finalize, older_than, read_limited_file, retain, watch, QueueSender, ReceivedFile,
+        CATCHUP_DELAY,
+    },
+    output::queue::QueuedKind,
+    stats::{send_stat, Event},
+    JobConfig,
+};
+use futures::{
+    future::{self, Either, Future},
+    lazy,
+    sync::mpsc,
+    Stream,
+};
+use slog::{slog_debug, slog_warn};
+use slog_scope::{debug, warn};
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+/// Inventory signatures are named after the inventory, with this extension added
+const SIGNATURE_EXTENSION: &str = "sign";
+
+pub fn serve_inventories(job_config: Arc<JobConfig>, stats: mpsc::Sender<Event>) {
+    let cfg = &job_config.cfg.processing.inventory;
+    let (tx, inventory_rx) = mpsc::channel(cfg.queue_depth);
+    let inventory_tx = QueueSender::new(tx, stats.clone(), Event::InventoryQueued);
+    tokio::spawn(treat_inventories(job_config.clone(), inventory_rx, stats));
+    watch(&cfg.directory.join("incoming"), cfg.catchup, &inventory_tx);
+    watch(
+        &cfg.directory.join("accepted-nodes-updates"),
+        cfg.catchup,
+        &inventory_tx,
+    );
+    retain(&cfg.directory, cfg.retention);
+}
+
+fn is_signature(file: &Path) -> bool {
+    file.extension()
+        .map(|extension| extension == SIGNATURE_EXTENSION)
+        .unwrap_or(false)
+}
+
+/// `node.ocs` is signed by `node.ocs.sign`. The agent signs inventories before compressing
+/// them, so `node.ocs.gz` is signed by `node.ocs.sign` too.
+fn signature_path(inventory: &Path) -> PathBuf {
+    let name = inventory
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    inventory.with_file_name(format!(
+        "{}.{}",
+        uncompressed_name(&name),
+        SIGNATURE_EXTENSION
+    ))
+}
+
+/// Returns the inventory signed by the signature, if it was received
+fn signed_inventory(signature: &Path) -> Option<PathBuf> {
+    let inventory = signature.with_extension("");
+    let name = inventory.file_name()?.to_string_lossy().to_string();
+    compressed_names(&name)
+        .into_iter()
+        .map(|name| inventory.with_file_name(name))
+        .find(|inventory| inventory.exists())
+}
+
+fn treat_inventories(
+    job_config: Arc<JobConfig>,
+    rx: mpsc::Receiver<ReceivedFile>,
+    stats: mpsc::Sender<Event>,
+) -> impl Future<Item = (), Error = ()> {
+    let workers = job_config.cfg.processing.inventory.workers;
+    rx.map(move |file| {
+        debug!("received: {:?}", file; "component" => "watcher");
+
+        let finish_stats = stats.clone();
+        let treat_file = if is_signature(&file) {
+            Either::A(treat_signature(file, job_config.clone(), stats.clone()))
+        } else {
+            Either::B(treat_pair(file, job_config.clone(), stats.clone()))
+        };
+
+        send_stat(stats.clone(), Event::InventoryStarted)
+            .and_then(|_| treat_file)
+            .then(move |_| send_stat(finish_stats, Event::InventoryFinished))
+    })
+    // Only take new files from the queue when a worker is available
+    .buffer_unordered(workers)
+    .for_each(|_| Ok(()))
+}
+
+/// Inventories are treated along with their signature. An inventory without signature
+/// is left in place until its signature arrives, or until it has been alone for too
+/// long, in which case it is forwarded unsigned.
+fn treat_pair(
+    file: ReceivedFile,
+    job_config: Arc<JobConfig>,
+    stats: mpsc::Sender<Event>,
+) -> impl Future<Item = (), Error = ()> {
+    let signature = signature_path(&file);
+    if !signature.exists() && !older_than(&file, SystemTime::now(), CATCHUP_DELAY) {
+        debug!("waiting for the signature of {:?}", file; "component" => "inventory");
+        return Either::B(future::ok(()));
+    }
+
+    // The signature is only claimed by the task that claimed the inventory
+    Either::A(claim(file).and_then(move |claimed| match claimed {
+        Some(file) => Either::A(claim(signature).and_then(move |signature| {
+            if signature.is_none() {
+                warn!("forwarding unsigned inventory: {:?}", file; "component" => "inventory");
+            }
+            treat_inventory(file, signature, job_config, stats)
+        })),
+        None => Either::B(future::ok(())),
+    }))
+}
+
+/// Signatures are treated along with their inventory. A signature alone for too long
+/// belongs to an inventory that was forwarded unsigned, and is forwarded after it.
+fn treat_signature(
+    file: ReceivedFile,
+    job_config: Arc<JobConfig>,
+    stats: mpsc::Sender<Event>,
+) -> impl Future<Item = (), Error = ()> {
+    if let Some(inventory) = signed_inventory(&file) {
+        return Either::A(Either::A(treat_pair(inventory, job_config, stats)));
+    }
+    if !older_than(&file, SystemTime::now(), CATCHUP_DELAY) {
+        return Either::B(future::ok(()));
+    }
+
+    warn!("forwarding signature without inventory: {:?}", file; "component" => "inventory");
+    Either::A(Either::B(claim(file).and_then(
+        move |claimed| match claimed {
+            Some(file) => Either::A(forward_signature(file, &job_config, stats)),
+            None => Either::B(future::ok(())),
+        },
+    )))
+}
+
+/// Queues a signature alone for upload, or archives it as failed
+fn forward_signature(
+    file: ReceivedFile,
+    job_config: &JobConfig,
+    stats: mpsc::Sender<Event>,
+) -> impl Future<Item = (), Error = ()> {
+    let cfg = &job_config.cfg.processing.inventory;
+    let directory = cfg.directory.clone();
+    let retention = cfg.retention;
+    let queued = match InventoryType::from_path(&file) {
+        Some(inventory_type) => Either::A(
+            job_config
+                .upstream_queue
+                .as_ref()
+                .expect("output uses upstream but no queue provided")
+                .push(&file, QueuedKind::Inventory(inventory_type)),
+        ),
+        None => Either::B(future::err(Error::InvalidInventory)),
+    };
+    queued.then(move |res| match res {
+        Ok(()) => Either::A(send_stat(stats, Event::UpstreamQueued)),
+        Err(e) => {
+            warn!("signature error on {:?}: {}", file, e; "component" => "inventory");
+            Either::B(finalize(file, directory, retention, &Err(e)))
+        }
+    })
+}
+
+/// Checks the file contains a complete OCS inventory, compressed or not
+fn validate(path: &Path, content: &[u8], limit: u64) -> Result<(), Error> {
+    let inventory = String::from_utf8(decompress(path, content.to_vec(), limit)?)?;
+    if inventory.contains("<REQUEST>") && inventory.trim_end().ends_with("</REQUEST>") {
+        Ok(())
+    } else {
+        Err(Error::InvalidInventory)
+    }
+}
+
+fn treat_inventory(
+    file: ReceivedFile,
+    signature: Option<ReceivedFile>,
+    job_config: Arc<JobConfig>,
+    stats: mpsc::Sender<Event>,
+) -> impl Future<Item = (), Error = ()> {
+    let stat_event = send_stat(stats.clone(), Event::InventoryReceived);
+    tokio::spawn(lazy(|| stat_event));
+
+    let cfg = &job_config.cfg.processing.inventory;
+    let limit = cfg.max_decompressed_size;
+    let inventory_type = InventoryType::from_path(&file);
+    let path = file.clone();
+    let send_file = file.clone();
+    let send_signature = signature.clone();
+    let send_config = job_config.clone();
+
+    let executor = job_config.processing_executor.clone();
+    let treat_file = read_limited_file(&file, limit)
+        .and_then(move |content| executor.spawn_fn(move || validate(&path, &content, limit)))
+        .and_then(move |_| inventory_type.ok_or(Error::InvalidInventory))
+        .and_then(
+            move |inventory_type| match send_config.cfg.processing.inventory.output {
+                InventoryOutputSelect::Upstream => {
+                    forward(&send_config, inventory_type, &send_file, send_signature)
+                }
+                InventoryOutputSelect::Disabled => unreachable!(),
+            },
+        );
+
+    let directory = cfg.directory.clone();
+    let retention = cfg.retention;
+    treat_file.then(move |res| {
+        let event = match res {
+            Ok(()) => {
+                // The upstream queue now owns the files
+                let queued_signature =
+                    signature.map(|_| send_stat(stats.clone(), Event::UpstreamQueued));
+                return Either::A(
+                    send_stat(stats, Event::UpstreamQueued)
+                        .join(queued_signature)
+                        .map(|_| ()),
+                );
+            }
+            Err(ref e) => {
+                warn!("inventory error on {:?}: {}", file, e; "component" => "inventory");
+                Event::InventoryRefused
+            }
+        };
+        let archive_signature =
+            signature.map(|signature| finalize(signature, directory.clone(), retention, &res));
+        Either::B(
+            send_stat(stats, event)
+                .join3(
+                    finalize(file, directory, retention, &res),
+                    archive_signature,
+                )
+                .map(|_| ()),
+        )
+    })
+}
+
+/// Queues the inventory for upload, then its signature if any
+fn forward(
+    job_config: &JobConfig,
+    inventory_type: InventoryType,
+    file: &Path,
+    signature: Option<ReceivedFile>,
+) -> impl Future<Item = (), Error = Error> {
+    let queue = job_config
+        .upstream_queue
+        .clone()
+        .expect("output uses upstream but no queue provided");
+    let kind = QueuedKind::Inventory(inventory_type);
+    queue.push(file, kind).and_then(move |_| match signature {
+        Some(signature) => Either::A(queue.push(&signature, kind)),
+        None => Either::B(future::ok(())),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        configuration::Configuration,
+        data::nodes::parse_nodeslist,
+        output::{breaker::CircuitBreaker, queue::UpstreamQueue},
+    };
+    use flate2::write::GzEncoder;
+    use std::{
+        fs::{create_dir_all, read_dir, read_to_string, remove_dir_all, write},
+        io::Write,
+    };
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn it_pairs_signatures() {
+        // Named by the agent, which signs inventories before compressing them
+        let signature = PathBuf::from("tests/tmp/inventories/incoming/node-1234.ocs.sign");
+        for inventory in &["node-1234.ocs", "node-1234.ocs.gz", "node-1234.ocs.zst"] {
+            let inventory = Path::new("tests/tmp/inventories/incoming").join(inventory);
+            assert!(!is_signature(&inventory));
+            assert_eq!(signature_path(&inventory), signature);
+        }
+        assert!(is_signature(&signature));
+    }
+
+    #[test]
+    fn it_validates_inventories() {
+        let inventory = b"<?xml version=\"1.0\" encoding=\"UTF-8\" ?>\n<REQUEST>\n<CONTENT>\n</CONTENT>\n</REQUEST>\n";
+        assert!(validate(Path::new("node.ocs"), inventory, 1024).is_ok());
+        assert!(validate(Path::new("node.ocs"), &inventory[..40], 1024).is_err());
+        assert!(validate(Path::new("node.ocs.gz"), inventory, 1024).is_err());
+    }
+
+    #[test]
+    fn it_waits_for_signatures() {
+        let directory = PathBuf::from("tests/tmp/test_inventory_pairing");
+        let incoming = directory.join("incoming");
+        let queue_directory = directory.join("queue");
+        let _ = remove_dir_all(&directory);
+        create_dir_all(&incoming).unwrap();
+        create_dir_all(directory.join("processing").join("incoming")).unwrap();
+        create_dir_all(&queue_directory).unwrap();
+
+        let mut cfg =
+            Configuration::read_configuration(&read_to_string("tests/files/relayd.conf").unwrap())
+                .unwrap();
+        cfg.processing.inventory.directory = directory.clone();
+        let job_config = Arc::new(JobConfig {
+            cfg,
+            nodes: parse_nodeslist(&read_to_string("tests/files/nodeslist.json").unwrap()).unwrap(),
+            pool: None,
+            database_executor: None,
+            processing_executor: crate::processing::executor(),
+            database_breaker: CircuitBreaker::new("database"),
+            client: None,
+            upstream_queue: Some(UpstreamQueue::new(&queue_directory).unwrap()),
+        });
+        let (stats, _rx) = mpsc::channel(1_024);
+        // The queue also contains its failed directory
+        let queued = || {
+            read_dir(&queue_directory)
+                .unwrap()
+                .filter(|entry| entry.as_ref().unwrap().path().is_file())
+                .count()
+        };
+
+        let inventory = incoming.join("node.ocs.gz");
+        let mut compressed = GzEncoder::new(vec![], flate2::Compression::default());
+        compressed
+            .write_all(b"<?xml version=\"1.0\" encoding=\"UTF-8\" ?>\n<REQUEST>\n</REQUEST>\n")
+            .unwrap();
+        write(&inventory, compressed.finish().unwrap()).unwrap();
+        let mut runtime = Runtime::new().unwrap();
+
+        // A recent inventory waits for its signature
+        runtime
+            .block_on(treat_pair(
+                inventory.clone(),
+                job_config.clone(),
+                stats.clone(),
+            ))
+            .unwrap();
+        assert!(inventory.exists());
+        assert_eq!(queued(), 0);
+
+        // and is queued along with it once it arrives
+        let signature = incoming.join("node.ocs.sign");
+        write(&signature, "signature").unwrap();
+        runtime
+            .block_on(treat_signature(signature.clone(), job_config, stats))
+            .unwrap();
+        assert!(!inventory.exists());
+        assert!(!signature.exists());
+        assert_eq!(queued(), 2);
+
+        // cleanup
+        let _ = remove_dir_all(&directory);
+    }
+}
@@ -0,0 +1,487 @@
+// Copyright 2019 Normation SAS
+//
+// This file is part of Rudder.
+//
+// Rudder is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In accordance with the terms of section 7 (7. Additional Terms.) of
+// the GNU General Public License version 3, the copyright holders add
+// the following Additional permissions:
+// Notwithstanding to the terms of section 5 (5. Conveying Modified Source
+// Versions) and 6 (6. Conveying Non-Source Forms.) of the GNU General
+// Public License version 3, when you create a Related Module, this
+// Related Module is not considered as a part of the work and may be
+// distributed under the license agreement of your choice.
+// A "Related Module" means a set of sources files including their
+// documentation that, without modification of the Source Code, enables
+// supplementary functions or services in addition to those offered by
+// the Software.
+//
+// Rudder is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::{
+    configuration::ReportingOutputSelect,
+    data::reporting::{RunInfo, RunLog, RunLogEnvelope},
+    error::Error,
+    input::{
+        claim, compression::decompress, drain, finalize, read_limited_file, retain, spool, watch,
+        QueueSender, ReceivedFile,
+    },
+    output::{
+        database::{check_connection, insert_runlog, RunlogInsertion},
+        queue::QueuedKind,
+        retry::retry,
+    },
+    signature::verify_runlog,
+    stats::{send_stat, Event},
+    JobConfig,
+};
+use futures::{
+    future::{self, Either, Future},
+    lazy,
+    sync::mpsc,
+    Stream,
+};
+use slog::{slog_debug, slog_info, slog_warn};
+use slog_scope::{debug, info, warn};
+use std::{path::Path, sync::Arc};
+
+pub fn serve_reports(job_config: Arc<JobConfig>, stats: mpsc::Sender<Event>) {
+    let cfg = &job_config.cfg.processing.reporting;
+    let (tx, reporting_rx) = mpsc::channel(cfg.queue_depth);
+    let reporting_tx = QueueSender::new(tx, stats.clone(), Event::ReportQueued);
+    tokio::spawn(treat_reports(
+        job_config.clone(),
+        reporting_rx,
+        stats.clone(),
+        cfg.workers,
+    ));
+    watch(&cfg.directory.join("incoming"), cfg.catchup, &reporting_tx);
+    retain(&cfg.directory, cfg.retention);
+
+    // Spooled runlogs are treated by a single worker, to be inserted in order
+    let (tx, spool_rx) = mpsc::channel(cfg.queue_depth);
+    let spool_tx = QueueSender::new(tx, stats.clone(), Event::ReportQueued);
+    tokio::spawn(treat_reports(job_config.clone(), spool_rx, stats, 1));
+    let probe_config = job_config.clone();
+    drain(
+        &cfg.directory,
+        cfg.catchup,
+        move || database_probe(&probe_config),
+        &spool_tx,
+    );
+}
+
+fn treat_reports(
+    job_config: Arc<JobConfig>,
+    rx: mpsc::Receiver<ReceivedFile>,
+    stats: mpsc::Sender<Event>,
+    workers: usize,
+) -> impl Future<Item = (), Error = ()> {
+    let breaker_config = job_config.clone();
+    // Do not take new files while the database is unavailable, the queue fills up
+    // and pauses the watchers
+    rx.and_then(move |file| {
+        let probe_config = breaker_config.clone();
+        breaker_config
+            .database_breaker
+            .wait_closed(
+                breaker_config.cfg.output.database.retry.max_delay,
+                move || database_probe(&probe_config),
+            )
+            // Errors are logged by the breaker, the file is treated anyway
+            .then(|_| Ok(file))
+    })
+    .map(move |file| {
+        debug!("received: {:?}", file; "component" => "watcher");
+
+        let job_config = job_config.clone();
+        let treat_stats = stats.clone();
+        let finish_stats = stats.clone();
+        send_stat(stats.clone(), Event::ReportStarted)
+            .and_then(move |_| claim(file))
+            .and_then(move |claimed| match claimed {
+                Some(file) => Either::A(treat_report(file, job_config, treat_stats)),
+                None => Either::B(future::ok(())),
+            })
+            .then(move |_| send_stat(finish_stats, Event::ReportFinished))
+    })
+    // Only take new files from the queue when a worker is available
+    .buffer_unordered(workers)
+    .for_each(|_| Ok(()))
+}
+
+fn treat_report(
+    file: ReceivedFile,
+    job_config: Arc<JobConfig>,
+    stats: mpsc::Sender<Event>,
+) -> impl Future<Item = (), Error = ()> {
+    let stat_event = send_stat(stats.clone(), Event::ReportReceived);
+    tokio::spawn(lazy(|| stat_event));
+
+    let cfg = &job_config.cfg.processing.reporting;
+    let treat_file = match cfg.output {
+        ReportingOutputSelect::Database => {
+            Either::A(insert(&file, job_config.clone(), stats.clone()))
+        }
+        ReportingOutputSelect::Upstream => Either::B(forward(&file, job_config.clone())),
+        ReportingOutputSelect::Disabled => unreachable!(),
+    };
+
+    let directory = cfg.directory.clone();
+    let retention = cfg.retention;
+    treat_file.then(move |res| {
+        let (event, res) = match res {
+            // The upstream queue now owns the file
+            Ok(Event::UpstreamQueued) => {
+                return Either::A(Either::B(send_stat(stats, Event::UpstreamQueued)));
+            }
+            Ok(event) => (event, Ok(())),
+            Err(ref e) if e.is_database_unavailable() => {
+                warn!("database unavailable, spooling {:?}: {}", file, e; "component" => "parser");
+                return Either::A(Either::A(
+                    spool(file, directory)
+                        .join(send_stat(stats, Event::ReportSpooled))
+                        .map(|_| ()),
+                ));
+            }
+            Err(e) => {
+                warn!("runlog error on {:?}: {}", file, e; "component" => "parser");
+                (Event::ReportRefused, Err(e))
+            }
+        };
+        Either::B(
+            finalize(file, directory, retention, &res)
+                .join(send_stat(stats, event))
+                .map(|_| ()),
+        )
+    })
+}
+
+/// Runlog files are named after their run
+fn run_info(path: &Path) -> Result<RunInfo, Error> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| Error::InvalidFileName(path.to_path_buf()))
+        .and_then(|name| name.parse::<RunInfo>())
+        .map_err(|e| e.in_file(path))
+}
+
+/// Counts a retry of the runlog insertion or upload
+fn retried(stats: &mpsc::Sender<Event>) {
+    let stat_event = send_stat(stats.clone(), Event::ReportRetried);
+    tokio::spawn(lazy(|| stat_event));
+}
+
+/// Parses the runlog and checks its signature, unless disabled, and its consistency with
+/// its file name and the nodes list
+fn check_runlog(
+    file: &Path,
+    file_info: Result<RunInfo, Error>,
+    content: &str,
+    job_config: &JobConfig,
+) -> Result<RunLog, Error> {
+    let cfg = &job_config.cfg.processing.reporting;
+    let envelope = RunLogEnvelope::parse(content).map_err(|e| e.in_file(file))?;
+    if !cfg.skip_signature {
+        verify_runlog(
+            &envelope,
+            &job_config.nodes,
+            &job_config.cfg.general.keys_directory,
+        )?;
+    }
+    let runlog = envelope
+        .runlog(cfg.parsing, &cfg.log_prefixes)
+        .map_err(|e| e.in_file(file))?;
+    for segment in &runlog.rejected {
+        warn!(
+            "skipped invalid report: {}: {:?}",
+            segment.error.clone().in_file(file),
+            segment.content;
+            "component" => "parser"
+        );
+    }
+    runlog.validate(&file_info?, &job_config.nodes)?;
+    Ok(runlog)
+}
+
+/// Reads the runlog, then decompresses and checks it on the processing executor
+fn read_runlog(
+    path: &ReceivedFile,
+    job_config: Arc<JobConfig>,
+) -> impl Future<Item = RunLog, Error = Error> {
+    let limit = job_config.cfg.processing.reporting.max_decompressed_size;
+    let file = path.clone();
+    let file_info = run_info(path);
+    read_limited_file(path, limit).and_then(move |content| {
+        let executor = job_config.processing_executor.clone();
+        executor.spawn_fn(move || {
+            let runlog = String::from_utf8(decompress(&file, content, limit)?)?;
+            check_runlog(&file, file_info, &runlog, &job_config)
+        })
+    })
+}
+
+/// Returns the statistics event matching the outcome
+fn insert(
+    path: &ReceivedFile,
+    job_config: Arc<JobConfig>,
+    stats: mpsc::Sender<Event>,
+) -> impl Future<Item = Event, Error = Error> {
+    let duplicate_file = path.clone();
+    read_runlog(path, job_config.clone())
+        .and_then(move |mut runlog| {
+            let cfg = &job_config.cfg.processing.reporting;
+            runlog
+                .reports
+                .retain(|report| !cfg.skip_event_types.contains(&report.event_type));
+            insert_with_retry(job_config, runlog, stats)
+        })
+        .map(move |insertion| insertion_event(insertion, &duplicate_file))
+}
+
+fn insertion_event(insertion: RunlogInsertion, file: &Path) -> Event {
+    match insertion {
+        RunlogInsertion::Inserted => Event::ReportInserted,
+        RunlogInsertion::AlreadyThere => {
+            info!("skipping already inserted runlog: {:?}", file; "component" => "parser");
+            Event::ReportDuplicate
+        }
+    }
+}
+
+/// Succeeds if the database is reachable
+fn database_probe(job_config: &JobConfig) -> impl Future<Item = (), Error = Error> {
+    let pool = job_config
+        .pool
+        .clone()
+        .expect("output uses database but no config provided");
+    job_config
+        .database_executor
+        .as_ref()
+        .expect("output uses database but no executor provided")
+        .spawn_fn(move || check_connection(&pool))
+}
+
+/// Inserts the runlog, retrying with exponential backoff while the database is unavailable.
+///
+/// The database breaker is opened at the first unavailability, to stop taking new runlogs,
+/// and closed after a successful insertion.
+fn insert_with_retry(
+    job_config: Arc<JobConfig>,
+    runlog: RunLog,
+    stats: mpsc::Sender<Event>,
+) -> impl Future<Item = RunlogInsertion, Error = Error> {
+    let runlog = Arc::new(runlog);
+    let cfg = job_config.cfg.output.database.retry;
+    let breaker = job_config.database_breaker.clone();
+    let attempts = cfg.attempts;
+    retry(
+        cfg,
+        Error::is_database_unavailable,
+        move || {
+            let pool = job_config
+                .pool
+                .clone()
+                .expect("output uses database but no config provided");
+            let batch_size = job_config.cfg.output.database.batch_size;
+            let runlog = runlog.clone();
+            let breaker = job_config.database_breaker.clone();
+            job_config
+                .database_executor
+                .as_ref()
+                .expect("output uses database but no executor provided")
+                .spawn_fn(move || insert_runlog(&pool, &runlog, batch_size))
+                .map_err(move |e| {
+                    if e.is_database_unavailable() {
+                        breaker.open();
+                    }
+                    e
+                })
+        },
+        move |attempt, e, delay| {
+            warn!(
+                "insertion attempt {}/{} failed: {}, retrying in {:?}",
+                attempt, attempts, e, delay;
+                "component" => "database"
+            );
+            retried(&stats);
+        },
+    )
+    .map(move |insertion| {
+        breaker.close();
+        insertion
+    })
+}
+
+/// Checks the runlog like before an insertion and queues it for upload unchanged, as the
+/// upstream server parses it again. Returns the statistics event matching the outcome.
+fn forward(
+    path: &ReceivedFile,
+    job_config: Arc<JobConfig>,
+) -> impl Future<Item = Event, Error = Error> {
+    let file = path.clone();
+    read_runlog(path, job_config.clone())
+        .and_then(move |_| {
+            job_config
+                .upstream_queue
+                .as_ref()
+                .expect("output uses upstream but no queue provided")
+                .push(&file, QueuedKind::Runlog)
+        })
+        .map(|_| Event::UpstreamQueued)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        configuration::Configuration,
+        data::{nodes::parse_nodeslist, reporting::Report},
+        fake,
+        output::{breaker::CircuitBreaker, queue::UpstreamQueue},
+        stats::Stats,
+    };
+    use chrono::Duration;
+    use futures::{stream, Sink};
+    use futures_cpupool::Builder;
+    use std::{
+        fs::{create_dir_all, read_dir, read_to_string, remove_dir_all, write},
+        path::PathBuf,
+        thread, time,
+    };
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn it_checks_unsigned_runlogs() {
+        let mut cfg =
+            Configuration::read_configuration(&read_to_string("tests/files/relayd.conf").unwrap())
+                .unwrap();
+        cfg.processing.reporting.skip_signature = true;
+        let job_config = JobConfig {
+            cfg,
+            nodes: parse_nodeslist(&read_to_string("tests/files/nodeslist.json").unwrap()).unwrap(),
+            pool: None,
+            database_executor: None,
+            processing_executor: crate::processing::executor(),
+            database_breaker: CircuitBreaker::new("database"),
+            client: None,
+            upstream_queue: None,
+        };
+        let content = read_to_string("tests/runlogs/2018-08-24T15:55:01+00:00@root.log").unwrap();
+        let check = |name: &str| {
+            let file = Path::new("tests/tmp/runlogs/incoming").join(name);
+            check_runlog(&file, run_info(&file), &content, &job_config)
+        };
+
+        assert!(check("2018-08-24T15:55:01+00:00@root.log").is_ok());
+        // The file name is the run the runlog is sent for
+        match check("2019-01-24T15:55:01+00:00@root.log") {
+            Err(Error::InconsistentRunlog(_)) => (),
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+        match check("2018-08-24T15:55:01+00:00@unknown.log") {
+            Err(Error::UnknownNode(_)) => (),
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+        assert!(check("runlog.log").is_err());
+    }
+
+    #[test]
+    fn it_treats_spooled_runlogs_in_order() {
+        let directory = Path::new("tests/tmp/test_spool_order");
+        let _ = remove_dir_all(directory);
+        let spool = directory.join("spool");
+        let queue = directory.join("queue");
+        create_dir_all(&spool).unwrap();
+        create_dir_all(directory.join("processing/spool")).unwrap();
+        create_dir_all(&queue).unwrap();
+
+        let mut cfg =
+            Configuration::read_configuration(&read_to_string("tests/files/relayd.conf").unwrap())
+                .unwrap();
+        cfg.processing.reporting.skip_signature = true;
+        cfg.processing.reporting.output = ReportingOutputSelect::Upstream;
+        let job_config = Arc::new(JobConfig {
+            cfg,
+            nodes: parse_nodeslist(&read_to_string("tests/files/nodeslist.json").unwrap()).unwrap(),
+            pool: None,
+            database_executor: None,
+            processing_executor: Builder::new().pool_size(4).create(),
+            database_breaker: CircuitBreaker::new("database"),
+            client: None,
+            upstream_queue: Some(UpstreamQueue::new(&queue).unwrap()),
+        });
+
+        // The first spooled runlog is the longest to treat, and the executor can treat
+        // the following ones meanwhile
+        let mut spooled = vec![];
+        for (i, size) in [20000, 1, 1, 1].iter().enumerate() {
+            let mut runlog = fake::reporting::runlog(Some("root".to_string()));
+            let timestamp = runlog.info.timestamp - Duration::minutes(i as i64);
+            runlog.reports = runlog
+                .reports
+                .iter()
+                .cycle()
+                .take(*size)
+                .map(|report| Report {
+                    start_datetime: timestamp,
+                    ..report.clone()
+                })
+                .collect();
+            let name = format!("{}@root.log", timestamp.to_rfc3339());
+            write(spool.join(&name), runlog.to_string()).unwrap();
+            spooled.push(name);
+            thread::sleep(time::Duration::from_millis(10));
+        }
+
+        let (tx, rx) = mpsc::channel(10);
+        let (stats, _stats_rx) = mpsc::channel(100);
+        let files: Vec<PathBuf> = spooled.iter().map(|name| spool.join(name)).collect();
+        let mut runtime = Runtime::new().unwrap();
+        runtime
+            .block_on(future::lazy(move || {
+                tx.send_all(stream::iter_ok(files))
+                    .map_err(|_| ())
+                    .and_then(move |_| treat_reports(job_config, rx, stats, 1))
+            }))
+            .unwrap();
+
+        // Queued files are named after their position in the queue, which also contains
+        // its failed directory
+        let mut queued: Vec<String> = read_dir(&queue)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.is_file())
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        queued.sort();
+        let queued: Vec<&str> = queued
+            .iter()
+            .map(|name| name.splitn(3, '@').nth(2).unwrap())
+            .collect();
+        assert_eq!(queued, spooled);
+
+        // cleanup
+        let _ = remove_dir_all(directory);
+    }
+
+    #[test]
+    fn it_counts_duplicate_runlogs() {
+        let file = Path::new("tests/tmp/runlogs/incoming/2018-08-24T15:55:01+00:00@root.log");
+        let mut stats = Stats::default();
+        stats.event(insertion_event(RunlogInsertion::Inserted, file));
+        stats.event(insertion_event(RunlogInsertion::AlreadyThere, file));
+        assert_eq!(stats.report_inserted, 1);
+        assert_eq!(stats.report_duplicate, 1);
+    }
+}
@@ -0,0 +1,174 @@
+// Copyright 2019 Normation SAS
+//
+// This file is part of Rudder.
+//
+// Rudder is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In accordance with the terms of section 7 (7. Additional Terms.) of
+// the GNU General Public License version 3, the copyright holders add
+// the following Additional permissions:
+// Notwithstanding to the terms of section 5 (5. Conveying Modified Source
+// Versions) and 6 (6. Conveying Non-Source Forms.) of the GNU General
+// Public License version 3, when you create a Related Module, this
+// Related Module is not considered as a part of the work and may be
+// distributed under the license agreement of your choice.
+// A "Related Module" means a set of sources files including their
+// documentation that, without modification of the Source Code, enables
+// supplementary functions or services in addition to those offered by
+// the Software.
+//
+// Rudder is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::{
+    data::{nodes::NodesList, reporting::RunLogEnvelope},
+    error::Error,
+};
+use openssl::{
+    base64::decode_block,
+    hash::MessageDigest,
+    pkey::{PKey, Public},
+    sha::sha256,
+    sign::Verifier,
+};
+use slog::slog_debug;
+use slog_scope::debug;
+use std::{fs::read, path::Path};
+
+/// Computes the key hash of a public key in the nodes list format,
+/// i.e. `sha256:` followed by the hex-encoded hash of the DER public key.
+pub fn key_hash(key: &PKey<Public>) -> Result<String, Error> {
+    let hash = sha256(&key.public_key_to_der()?);
+    Ok(format!(
+        "sha256:{}",
+        hash.iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>()
+    ))
+}
+
+/// Node public keys are stored as `<node_id>.pem` in the keys directory
+fn node_key(keys_directory: &Path, node_id: &str) -> Result<PKey<Public>, Error> {
+    let pem = read(keys_directory.join(format!("{}.pem", node_id)))?;
+    Ok(PKey::public_key_from_pem(&pem)?)
+}
+
+/// Checks that a runlog was signed by the node it claims to come from.
+///
+/// The key hash from the header must match the one in the nodes list, and so must
+/// the hash of the key we hold for the node. The signature is a base64-encoded
+/// RSA SHA-256 signature of the data section.
+///
+/// The header is not covered by the signature, it is checked against the signed
+/// reports by `RunLog::validate`, which needs to run on every verified runlog.
+pub fn verify_runlog(
+    envelope: &RunLogEnvelope,
+    nodes: &NodesList,
+    keys_directory: &Path,
+) -> Result<(), Error> {
+    let header = envelope.header.as_ref().ok_or(Error::MissingSignature)?;
+    let node_id = &header.node_id;
+
+    let node = nodes
+        .get(node_id)
+        .ok_or_else(|| Error::UnknownNode(node_id.clone()))?;
+    if header.key_hash != node.key_hash {
+        return Err(Error::KeyHashMismatch(node_id.clone()));
+    }
+
+    let key = node_key(keys_directory, node_id)?;
+    if key_hash(&key)? != node.key_hash {
+        return Err(Error::KeyHashMismatch(node_id.clone()));
+    }
+
+    let signature =
+        decode_block(&header.signature).map_err(|_| Error::InvalidSignature(node_id.clone()))?;
+    let mut verifier = Verifier::new(MessageDigest::sha256(), &key)?;
+    verifier.update(envelope.data.as_bytes())?;
+    if verifier.verify(&signature)? {
+        debug!("Valid signature for runlog of {}", node_id; "component" => "signature");
+        Ok(())
+    } else {
+        Err(Error::InvalidSignature(node_id.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{nodes::NodeInfo, reporting::RunLogHeader};
+    use chrono::DateTime;
+    use openssl::{base64::encode_block, rsa::Rsa, sign::Signer};
+    use std::{
+        collections::HashMap,
+        fs::{create_dir_all, write},
+    };
+
+    #[test]
+    fn it_verifies_runlog_signatures() {
+        let keys_directory = Path::new("tests/tmp/test_keys");
+        create_dir_all(keys_directory).unwrap();
+
+        let private = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let public = PKey::public_key_from_pem(&private.public_key_to_pem().unwrap()).unwrap();
+        write(
+            keys_directory.join("root.pem"),
+            private.public_key_to_pem().unwrap(),
+        )
+        .unwrap();
+        let hash = key_hash(&public).unwrap();
+
+        let data = "R: @@Common@@control@@rudder@@run@@0@@end@@20180824-130007-3ad37587@@2018-08-24 15:55:01+00:00##root@#End execution\n";
+        let mut signer = Signer::new(MessageDigest::sha256(), &private).unwrap();
+        signer.update(data.as_bytes()).unwrap();
+        let signature = encode_block(&signer.sign_to_vec().unwrap());
+
+        let mut nodes = HashMap::new();
+        nodes.insert(
+            "root".to_string(),
+            NodeInfo {
+                hostname: "server.rudder.local".to_string(),
+                policy_server: "root".to_string(),
+                key_hash: hash.clone(),
+            },
+        );
+        let mut envelope = RunLogEnvelope {
+            header: Some(RunLogHeader {
+                format: 0,
+                node_id: "root".to_string(),
+                timestamp: DateTime::parse_from_str("2018-08-24T15:55:01+00:00", "%+").unwrap(),
+                key_hash: hash,
+                signature,
+            }),
+            data,
+            data_line: 7,
+        };
+        assert!(verify_runlog(&envelope, &nodes, keys_directory).is_ok());
+
+        envelope.data = "R: tampered\n";
+        match verify_runlog(&envelope, &nodes, keys_directory) {
+            Err(Error::InvalidSignature(_)) => (),
+            _ => panic!("tampered runlog should be refused"),
+        }
+
+        envelope.header.as_mut().unwrap().key_hash = "sha256:00".to_string();
+        match verify_runlog(&envelope, &nodes, keys_directory) {
+            Err(Error::KeyHashMismatch(_)) => (),
+            _ => panic!("wrong key hash should be refused"),
+        }
+
+        envelope.header = None;
+        match verify_runlog(&envelope, &nodes, keys_directory) {
+            Err(Error::MissingSignature) => (),
+            _ => panic!("unsigned runlog should be refused"),
+        }
+    }
+}
@@ -28,10 +28,10 @@
 // You should have received a copy of the GNU General Public License
 // along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
 
-use futures::{stream::Stream, sync::mpsc, Future};
+use futures::{stream::Stream, sync::mpsc, Future, Sink};
 use serde::Serialize;
-use slog::slog_trace;
-use slog_scope::trace;
+use slog::{slog_trace, slog_warn};
+use slog_scope::{trace, warn};
 use std::sync::{Arc, RwLock};
 
 #[derive(Debug, Clone, Serialize, PartialEq, Eq, Default)]
@@ -40,9 +40,31 @@ pub struct Stats {
     pub report_refused: u64,
     pub report_sent: u64,
     pub report_inserted: u64,
+    /// Runlogs already inserted, skipped
+    pub report_duplicate: u64,
+    /// Runlogs put in the spool while the database was unavailable
+    pub report_spooled: u64,
+    /// Insertions or uploads of runlogs attempted again after a failure
+    pub report_retried: u64,
+    /// Files moved to the upstream queue
+    pub upstream_queued: u64,
+    /// Queued files dropped because of the queue limits
+    pub upstream_dropped: u64,
+    /// Files in the upstream queue
+    pub upstream_queue_length: u64,
+    /// Age in seconds of the oldest file in the upstream queue
+    pub upstream_queue_age: u64,
     pub inventory_received: u64,
     pub inventory_refused: u64,
     pub inventory_sent: u64,
+    /// Runlogs waiting for a worker
+    pub report_queued: u64,
+    /// Runlogs being treated
+    pub report_workers: u64,
+    /// Inventories waiting for a worker
+    pub inventory_queued: u64,
+    /// Inventories being treated
+    pub inventory_workers: u64,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -51,9 +73,25 @@ pub enum Event {
     ReportSent,
     ReportInserted,
     ReportRefused,
+    ReportDuplicate,
+    ReportSpooled,
+    ReportRetried,
+    UpstreamQueued,
+    UpstreamDropped,
+    /// Current state of the upstream queue
+    UpstreamQueue {
+        length: u64,
+        age: u64,
+    },
     InventoryReceived,
     InventorySent,
     InventoryRefused,
+    ReportQueued,
+    ReportStarted,
+    ReportFinished,
+    InventoryQueued,
+    InventoryStarted,
+    InventoryFinished,
 }
 
 impl Stats {
@@ -63,13 +101,44 @@ impl Stats {
             Event::ReportSent => self.report_sent += 1,
             Event::ReportInserted => self.report_inserted += 1,
             Event::ReportRefused => self.report_refused += 1,
+            Event::ReportDuplicate => self.report_duplicate += 1,
+            Event::ReportSpooled => self.report_spooled += 1,
+            Event::ReportRetried => self.report_retried += 1,
+            Event::UpstreamQueued => self.upstream_queued += 1,
+            Event::UpstreamDropped => self.upstream_dropped += 1,
+            Event::UpstreamQueue { length, age } => {
+                self.upstream_queue_length = length;
+                self.upstream_queue_age = age;
+            }
             Event::InventoryReceived => self.inventory_received += 1,
             Event::InventorySent => self.inventory_sent += 1,
             Event::InventoryRefused => self.inventory_refused += 1,
+            Event::ReportQueued => self.report_queued += 1,
+            Event::ReportStarted => {
+                self.report_queued = self.report_queued.saturating_sub(1);
+                self.report_workers += 1
+            }
+            Event::ReportFinished => self.report_workers = self.report_workers.saturating_sub(1),
+            Event::InventoryQueued => self.inventory_queued += 1,
+            Event::InventoryStarted => {
+                self.inventory_queued = self.inventory_queued.saturating_sub(1);
+                self.inventory_workers += 1
+            }
+            Event::InventoryFinished => {
+                self.inventory_workers = self.inventory_workers.saturating_sub(1)
+            }
         }
     }
 }
 
+/// Sends a statistics event, failures are only logged
+pub fn send_stat(stats: mpsc::Sender<Event>, event: Event) -> impl Future<Item = (), Error = ()> {
+    stats
+        .send(event)
+        .map_err(|e| warn!("send error: {}", e; "component" => "statistics"))
+        .map(|_| ())
+}
+
 pub fn stats_job(
     stats: Arc<RwLock<Stats>>,
     rx: mpsc::Receiver<Event>,
@@ -83,3 +152,21 @@ pub fn stats_job(
         Ok(())
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_tracks_queue_and_workers() {
+        let mut stats = Stats::default();
+        stats.event(Event::ReportQueued);
+        stats.event(Event::ReportQueued);
+        stats.event(Event::ReportStarted);
+        assert_eq!(stats.report_queued, 1);
+        assert_eq!(stats.report_workers, 1);
+        stats.event(Event::ReportFinished);
+        stats.event(Event::ReportFinished);
+        assert_eq!(stats.report_workers, 0);
+    }
+}
@@ -6,3 +6,5 @@ grant select on table ruddersysevents to rudderreports;
 grant insert on table ruddersysevents to rudderreports;
 /* grant truncate on table ruddersysevents to rudderreports; */
 grant usage on sequence serial to rudderreports;
+grant select on table reportsexecution to rudderreports;
+grant insert on table reportsexecution to rudderreports;